@@ -0,0 +1,159 @@
+//! Audio read from WAV and FLAC files.
+
+use crate::errors::*;
+
+use std::{path::Path, time::Instant};
+
+use hound::{SampleFormat, WavReader};
+
+use super::{sample_to_f32, AudioInput, Pacer, SampleBlock};
+
+/// A recording, delivered in blocks like a capture device.
+#[derive(Clone)]
+pub struct RecordedInput {
+    channels: Vec<Vec<f32>>,
+    sample_rate: u32,
+    frames: usize,
+    /// The frames delivered so far.
+    position: usize,
+    /// Waits between the blocks if the recording is played in real time.
+    pacer: Option<Pacer>,
+}
+
+impl RecordedInput {
+    /// Deliver the samples of every channel in blocks of `frames`.
+    ///
+    /// In `realtime`, the blocks are delivered as fast as they would be
+    /// captured, otherwise as fast as they are taken.
+    pub fn new(channels: Vec<Vec<f32>>, sample_rate: u32, frames: usize, realtime: bool) -> RecordedInput {
+        RecordedInput {
+            channels,
+            sample_rate,
+            frames,
+            position: 0,
+            pacer: if realtime { Some(Pacer::new(sample_rate)) } else { None },
+        }
+    }
+
+    /// Read a WAV or FLAC file, told apart by their extension.
+    pub fn open(path: &Path, frames: usize, realtime: bool) -> Result<RecordedInput> {
+        let flac = path
+            .extension()
+            .map(|extension| extension.eq_ignore_ascii_case("flac"))
+            .unwrap_or(false);
+        let (samples, channels, sample_rate) = if flac { read_flac(path)? } else { read_wav(path)? };
+
+        let channels = channels.max(1);
+        let channel_samples = (0..channels)
+            .map(|channel| samples.iter().skip(channel).step_by(channels).map(|sample| sample_to_f32(*sample)).collect())
+            .collect();
+        Ok(RecordedInput::new(channel_samples, sample_rate, frames, realtime))
+    }
+}
+
+impl AudioInput for RecordedInput {
+    fn start(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn next_block(&mut self) -> Result<Option<SampleBlock>> {
+        let end = self.position + self.frames;
+        if self.frames == 0 || self.channels.iter().any(|channel| channel.len() < end) {
+            return Ok(None);
+        }
+        let channels = self
+            .channels
+            .iter()
+            .map(|channel| channel[self.position..end].to_vec())
+            .collect();
+        self.position = end;
+        let timestamp = match self.pacer.as_mut() {
+            Some(pacer) => pacer.wait(self.frames),
+            None => Instant::now(),
+        };
+        Ok(Some(SampleBlock { channels, timestamp }))
+    }
+
+    fn channels(&self) -> usize {
+        self.channels.len()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+}
+
+/// Convert a sample of `bits` to 16 bit, to detect pitches in the same
+/// samples as from a capture device.
+fn to_i16(sample: i32, bits: u32) -> i16 {
+    if bits > 16 {
+        (sample >> (bits - 16)) as i16
+    } else {
+        (sample << (16 - bits)) as i16
+    }
+}
+
+/// The interleaved samples, the number of channels and the sample rate of a WAV file.
+fn read_wav(path: &Path) -> Result<(Vec<i16>, usize, u32)> {
+    let reader = WavReader::open(path).chain_err(|| format!("could not open {}", path.display()))?;
+    let spec = reader.spec();
+    let invalid = || format!("invalid WAV file {}", path.display());
+
+    let samples = match spec.sample_format {
+        SampleFormat::Int => reader
+            .into_samples::<i32>()
+            .map(|sample| sample.map(|sample| to_i16(sample, spec.bits_per_sample as u32)))
+            .collect::<std::result::Result<_, _>>()
+            .chain_err(invalid)?,
+        SampleFormat::Float => reader
+            .into_samples::<f32>()
+            .map(|sample| sample.map(|sample| (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16))
+            .collect::<std::result::Result<_, _>>()
+            .chain_err(invalid)?,
+    };
+    Ok((samples, spec.channels as usize, spec.sample_rate))
+}
+
+/// The interleaved samples, the number of channels and the sample rate of a FLAC file.
+fn read_flac(path: &Path) -> Result<(Vec<i16>, usize, u32)> {
+    let mut reader = claxon::FlacReader::open(path).chain_err(|| format!("could not open {}", path.display()))?;
+    let info = reader.streaminfo();
+    let samples = reader
+        .samples()
+        .map(|sample| sample.map(|sample| to_i16(sample, info.bits_per_sample)))
+        .collect::<std::result::Result<_, _>>()
+        .chain_err(|| format!("invalid FLAC file {}", path.display()))?;
+    Ok((samples, info.channels as usize, info.sample_rate))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_wav_files_in_blocks() {
+        let file = tempfile::Builder::new().suffix(".wav").tempfile().unwrap();
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: 22_050,
+            bits_per_sample: 16,
+            sample_format: SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(file.path(), spec).unwrap();
+        for _ in 0..10 {
+            writer.write_sample(i16::MAX / 2).unwrap();
+            writer.write_sample(0i16).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let mut input = RecordedInput::open(file.path(), 4, false).unwrap();
+        assert_eq!(input.sample_rate(), 22_050);
+        assert_eq!(input.channels(), 2);
+        let block = input.next_block().unwrap().unwrap();
+        assert_eq!(block.channels[0], vec![sample_to_f32(i16::MAX / 2); 4]);
+        assert_eq!(block.channels[1], vec![0.0; 4]);
+        // the last two frames don't fill a block
+        assert!(input.next_block().unwrap().is_some());
+        assert!(input.next_block().unwrap().is_none());
+    }
+}
@@ -0,0 +1,155 @@
+//! Sources of the audio the pitches of the singers are detected in.
+//!
+//! Every backend delivers blocks of a fixed number of frames, the capture
+//! threads don't care where they come from.  Besides capture devices, audio
+//! can be read from files and generated, to sing without a microphone.
+
+mod file;
+mod openal;
+mod pipeline;
+mod tone;
+
+use crate::errors::*;
+
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::capture::CaptureSettings;
+
+pub use self::file::RecordedInput;
+pub use self::tone::ToneInput;
+
+/// A block of samples from an audio input.
+#[derive(Clone, Debug)]
+pub struct SampleBlock {
+    /// The samples of every channel, all of the same length.
+    pub channels: Vec<Vec<f32>>,
+    /// When the last sample of the block was captured.
+    pub timestamp: Instant,
+}
+
+/// A source of audio delivered in blocks.
+pub trait AudioInput: Send {
+    /// Start delivering samples.
+    fn start(&mut self) -> Result<()>;
+
+    /// Wait for the next block of samples.
+    ///
+    /// Returns `None` once the input has ended, e.g. at the end of a file.
+    fn next_block(&mut self) -> Result<Option<SampleBlock>>;
+
+    /// The number of channels of every block.
+    fn channels(&self) -> usize;
+
+    fn sample_rate(&self) -> u32;
+}
+
+/// Where the audio comes from.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// A capture device opened with OpenAL.
+    OpenAl,
+    /// A GStreamer source element, like `autoaudiosrc` or `pulsesrc`.
+    GStreamer,
+    /// A WAV or FLAC file, played in real time.
+    File,
+    /// A sine tone, the device is its frequency in Hz.
+    Tone,
+}
+
+impl std::str::FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Backend, String> {
+        match s {
+            "openal" => Ok(Backend::OpenAl),
+            "gstreamer" => Ok(Backend::GStreamer),
+            "file" => Ok(Backend::File),
+            "tone" => Ok(Backend::Tone),
+            _ => Err(format!("unknown input backend `{}`", s)),
+        }
+    }
+}
+
+/// Open the input `device` of the configured backend, `None` for its default.
+///
+/// `channels` is the number of channels needed, files keep their own.
+pub fn open(device: Option<&str>, channels: usize, settings: &CaptureSettings) -> Result<Box<dyn AudioInput>> {
+    Ok(match settings.backend {
+        Backend::OpenAl => {
+            if channels > 2 {
+                return Err(format!(
+                    "OpenAL captures at most two channels, use the gstreamer backend for channel {}",
+                    channels - 1
+                ).into());
+            }
+            Box::new(openal::OpenAlInput::open(device, channels > 1, settings)?)
+        }
+        Backend::GStreamer => Box::new(pipeline::PipelineInput::open(device, channels, settings)?),
+        Backend::File => {
+            let path = device.chain_err(|| "the file backend needs the file to play as input device")?;
+            Box::new(RecordedInput::open(path.as_ref(), settings.frames, true)?)
+        }
+        Backend::Tone => {
+            let frequency = match device {
+                Some(frequency) => frequency
+                    .parse()
+                    .chain_err(|| format!("the tone backend needs a frequency in Hz, not \"{}\"", frequency))?,
+                None => tone::DEFAULT_FREQUENCY,
+            };
+            Box::new(ToneInput::new(frequency, channels, settings.sample_rate, settings.frames, true))
+        }
+    })
+}
+
+/// The names of all capture devices and the name of the default one.
+pub fn list_devices(backend: Backend) -> Result<(Vec<String>, Option<String>)> {
+    match backend {
+        Backend::OpenAl => openal::list_devices(),
+        _ => Err("only the openal backend can list its devices".into()),
+    }
+}
+
+/// Convert a 16 bit sample to the level the pitch detection works with.
+pub fn sample_to_f32(sample: i16) -> f32 {
+    (sample as f32) / (i16::MAX as f32) * 2.0
+}
+
+/// Delivers blocks no faster than they would be captured.
+#[derive(Clone)]
+struct Pacer {
+    sample_rate: u32,
+    /// When the first block was started.
+    start: Option<Instant>,
+    /// The frames delivered since the start.
+    frames: u64,
+}
+
+impl Pacer {
+    fn new(sample_rate: u32) -> Pacer {
+        Pacer {
+            sample_rate,
+            start: None,
+            frames: 0,
+        }
+    }
+
+    /// Wait until `frames` more frames would have been captured.
+    ///
+    /// Returns when the last of them was captured.
+    fn wait(&mut self, frames: usize) -> Instant {
+        let start = *self.start.get_or_insert_with(Instant::now);
+        self.frames += frames as u64;
+        let due = start + Duration::from_secs_f64(self.frames as f64 / self.sample_rate as f64);
+        let now = Instant::now();
+        if due > now {
+            thread::sleep(due - now);
+        }
+        due
+    }
+}
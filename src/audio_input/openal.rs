@@ -0,0 +1,154 @@
+//! Capture devices opened with OpenAL.
+
+use crate::errors::*;
+
+use std::{
+    ffi::CString,
+    thread,
+    time::{Duration, Instant},
+};
+
+use alto::{Alto, Capture, Mono, Stereo};
+
+use super::{sample_to_f32, AudioInput, SampleBlock};
+use crate::capture::CaptureSettings;
+
+/// The names of all capture devices and the name of the default one.
+pub fn list_devices() -> Result<(Vec<String>, Option<String>)> {
+    let alto = load()?;
+    let devices = alto
+        .enumerate_captures()
+        .into_iter()
+        .map(|name| name.to_string_lossy().into_owned())
+        .collect();
+    let default_device = alto
+        .default_capture()
+        .map(|name| name.to_string_lossy().into_owned());
+    Ok((devices, default_device))
+}
+
+fn load() -> Result<Alto> {
+    Alto::load_default().chain_err(|| "could not load openal default implementation")
+}
+
+enum Device {
+    Mono(Capture<Mono<i16>>),
+    Stereo(Capture<Stereo<i16>>),
+}
+
+/// An opened capture device.
+pub struct OpenAlInput {
+    device: Device,
+    sample_rate: u32,
+    frames: i32,
+}
+
+impl OpenAlInput {
+    /// Open a capture device, `None` for the default device of the system.
+    ///
+    /// Stereo devices capture both channels, mono devices mix them.
+    pub fn open(device: Option<&str>, stereo: bool, settings: &CaptureSettings) -> Result<OpenAlInput> {
+        let alto = load()?;
+        let name = match device {
+            Some(name) => {
+                let (devices, _) = list_devices()?;
+                if !devices.iter().any(|device| device == name) {
+                    return Err(format!(
+                        "unknown capture device \"{}\", see --list-input-devices for available ones",
+                        name
+                    ).into());
+                }
+                CString::new(name).chain_err(|| "invalid capture device name")?
+            }
+            None => alto
+                .default_capture()
+                .chain_err(|| "no default capture device found")?,
+        };
+        let frames = i32::try_from(settings.frames).chain_err(|| "the buffer is too long for OpenAL")?;
+        let describe = || {
+            format!(
+                "capture device {:?} does not support 16 bit {} at {} Hz with a buffer of {} frames",
+                name,
+                if stereo { "stereo" } else { "mono" },
+                settings.sample_rate,
+                settings.frames
+            )
+        };
+        let device = if stereo {
+            Device::Stereo(
+                alto.open_capture(Some(&name), settings.sample_rate, frames)
+                    .chain_err(describe)?,
+            )
+        } else {
+            Device::Mono(
+                alto.open_capture(Some(&name), settings.sample_rate, frames)
+                    .chain_err(describe)?,
+            )
+        };
+        Ok(OpenAlInput {
+            device,
+            sample_rate: settings.sample_rate,
+            frames,
+        })
+    }
+
+    fn samples_len(&self) -> i32 {
+        match &self.device {
+            Device::Mono(capture) => capture.samples_len(),
+            Device::Stereo(capture) => capture.samples_len(),
+        }
+    }
+}
+
+impl AudioInput for OpenAlInput {
+    fn start(&mut self) -> Result<()> {
+        match &mut self.device {
+            Device::Mono(capture) => capture.start(),
+            Device::Stereo(capture) => capture.start(),
+        }
+        Ok(())
+    }
+
+    fn next_block(&mut self) -> Result<Option<SampleBlock>> {
+        // OpenAL can't wait for samples, sleep until the missing ones should be there
+        loop {
+            let missing = self.frames - self.samples_len();
+            if missing <= 0 {
+                break;
+            }
+            thread::sleep(Duration::from_secs_f64(missing as f64 / self.sample_rate as f64));
+        }
+        let timestamp = Instant::now();
+        let frames = self.frames as usize;
+        let channels = match &mut self.device {
+            Device::Mono(capture) => {
+                let mut buffer: Vec<i16> = vec![0; frames];
+                capture
+                    .capture_samples(&mut buffer)
+                    .chain_err(|| "could not capture samples")?;
+                vec![buffer.iter().map(|x| sample_to_f32(*x)).collect()]
+            }
+            Device::Stereo(capture) => {
+                let mut buffer: Vec<[i16; 2]> = vec![[0; 2]; frames];
+                capture
+                    .capture_samples(&mut buffer)
+                    .chain_err(|| "could not capture samples")?;
+                (0..2)
+                    .map(|channel| buffer.iter().map(|x| sample_to_f32(x[channel])).collect())
+                    .collect()
+            }
+        };
+        Ok(Some(SampleBlock { channels, timestamp }))
+    }
+
+    fn channels(&self) -> usize {
+        match self.device {
+            Device::Mono(_) => 1,
+            Device::Stereo(_) => 2,
+        }
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+}
@@ -0,0 +1,102 @@
+//! Audio captured by a GStreamer source element.
+
+use crate::errors::*;
+
+use super::{AudioInput, SampleBlock};
+use crate::capture::CaptureSettings;
+use crate::gst::{self, prelude::*};
+
+/// A source element, converted to float samples and pulled from an app sink.
+pub struct PipelineInput {
+    pipeline: gst::Pipeline,
+    /// The `appsink` element, driven through its action signals.
+    sink: gst::Element,
+    channels: usize,
+    sample_rate: u32,
+    frames: usize,
+    /// Interleaved samples pulled but not delivered yet.
+    pending: Vec<f32>,
+}
+
+impl PipelineInput {
+    /// Capture from the configured source element, `device` sets its
+    /// `device` property if given.
+    pub fn open(device: Option<&str>, channels: usize, settings: &CaptureSettings) -> Result<PipelineInput> {
+        let mut source = settings.gstreamer_source.clone();
+        if let Some(device) = device {
+            source.push_str(&format!(" device=\"{}\"", device.replace('"', "\\\"")));
+        }
+        let description = format!(
+            "{} ! audioconvert ! audioresample ! appsink name=sink sync=false \
+             caps=audio/x-raw,format=F32LE,layout=interleaved,rate={},channels={}",
+            source, settings.sample_rate, channels
+        );
+        let pipeline = gst::parse_launch(&description)
+            .chain_err(|| format!("could not create GStreamer pipeline \"{}\"", description))?
+            .downcast::<gst::Pipeline>()
+            .map_err(|_| "the GStreamer source is not a pipeline")?;
+        let sink = pipeline
+            .by_name("sink")
+            .chain_err(|| "could not find the app sink of the GStreamer pipeline")?;
+        Ok(PipelineInput {
+            pipeline,
+            sink,
+            channels,
+            sample_rate: settings.sample_rate,
+            frames: settings.frames,
+            pending: Vec::new(),
+        })
+    }
+}
+
+impl AudioInput for PipelineInput {
+    fn start(&mut self) -> Result<()> {
+        self.pipeline
+            .set_state(gst::State::Playing)
+            .chain_err(|| "could not start the GStreamer source")?;
+        Ok(())
+    }
+
+    fn next_block(&mut self) -> Result<Option<SampleBlock>> {
+        let block_len = self.frames * self.channels;
+        while self.pending.len() < block_len {
+            // there is no sample at the end of the stream, or if the source failed
+            let sample = match self.sink.emit_by_name::<Option<gst::Sample>>("pull-sample", &[]) {
+                Some(sample) => sample,
+                None if self.sink.property::<bool>("eos") => return Ok(None),
+                None => return Err("the GStreamer source stopped".into()),
+            };
+            let buffer = sample.buffer().chain_err(|| "the GStreamer source sent no samples")?;
+            let map = buffer
+                .map_readable()
+                .chain_err(|| "could not read the samples of the GStreamer source")?;
+            self.pending.extend(
+                map.as_slice()
+                    .chunks_exact(4)
+                    .map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])),
+            );
+        }
+        let timestamp = std::time::Instant::now();
+
+        // same level as 16 bit samples converted by `sample_to_f32`
+        let block: Vec<f32> = self.pending.drain(..block_len).map(|sample| sample * 2.0).collect();
+        let channels = (0..self.channels)
+            .map(|channel| block.iter().skip(channel).step_by(self.channels).copied().collect())
+            .collect();
+        Ok(Some(SampleBlock { channels, timestamp }))
+    }
+
+    fn channels(&self) -> usize {
+        self.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+}
+
+impl Drop for PipelineInput {
+    fn drop(&mut self) {
+        let _ = self.pipeline.set_state(gst::State::Null);
+    }
+}
@@ -0,0 +1,71 @@
+//! A generated sine tone, to try the player without singing.
+
+use crate::errors::*;
+
+use std::time::Instant;
+
+use super::{AudioInput, Pacer, SampleBlock};
+
+/// The frequency of the tone if none is given, A4.
+pub const DEFAULT_FREQUENCY: f32 = 440.0;
+/// The level of the tone, well above the thresholds of the pitch detection.
+const AMPLITUDE: f32 = 0.5;
+
+/// An endless sine tone on every channel.
+pub struct ToneInput {
+    frequency: f32,
+    channels: usize,
+    sample_rate: u32,
+    frames: usize,
+    /// The frames generated so far.
+    position: u64,
+    /// Waits between the blocks if the tone is generated in real time.
+    pacer: Option<Pacer>,
+}
+
+impl ToneInput {
+    /// Generate `frequency` Hz in blocks of `frames`.
+    ///
+    /// In `realtime`, the blocks are delivered as fast as they would be
+    /// captured, otherwise as fast as they are taken.
+    pub fn new(frequency: f32, channels: usize, sample_rate: u32, frames: usize, realtime: bool) -> ToneInput {
+        ToneInput {
+            frequency,
+            channels: channels.max(1),
+            sample_rate,
+            frames,
+            position: 0,
+            pacer: if realtime { Some(Pacer::new(sample_rate)) } else { None },
+        }
+    }
+}
+
+impl AudioInput for ToneInput {
+    fn start(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn next_block(&mut self) -> Result<Option<SampleBlock>> {
+        let phase_step = 2.0 * std::f64::consts::PI * self.frequency as f64 / self.sample_rate as f64;
+        let samples: Vec<f32> = (0..self.frames as u64)
+            .map(|i| ((self.position + i) as f64 * phase_step).sin() as f32 * AMPLITUDE)
+            .collect();
+        self.position += self.frames as u64;
+        let timestamp = match self.pacer.as_mut() {
+            Some(pacer) => pacer.wait(self.frames),
+            None => Instant::now(),
+        };
+        Ok(Some(SampleBlock {
+            channels: vec![samples; self.channels],
+            timestamp,
+        }))
+    }
+
+    fn channels(&self) -> usize {
+        self.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+}
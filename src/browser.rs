@@ -0,0 +1,528 @@
+//! A full-screen browser to pick a song from the library or the server.
+
+use crate::errors::*;
+
+use std::{
+    io::{stdout, Write},
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant},
+};
+
+use termion::{
+    clear, cursor, event::Key, input::TermRead, raw::IntoRawMode, screen::AlternateScreen, style,
+};
+
+use crate::content_providers::get_url_content_provider;
+use crate::library::{Library, SongEntry};
+use crate::playback::Preview;
+use crate::query::Query;
+use crate::server_interface::{self, SearchResult};
+
+/// Rows above the song list: filter, help and a gap.
+const HEADER_ROWS: u16 = 3;
+/// Columns of the details pane.
+const DETAILS_WIDTH: u16 = 40;
+/// How long a song has to stay selected before its preview starts.
+const PREVIEW_DELAY: Duration = Duration::from_millis(400);
+/// How often to check for key presses.
+const INPUT_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// The song picked in the browser.
+pub enum Selection {
+    /// The path of a song file of the library.
+    Local(PathBuf),
+    /// A song on a server, to be downloaded.
+    Remote(SearchResult),
+}
+
+/// The column the songs are sorted by.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SortKey {
+    Artist,
+    Title,
+    Genre,
+    Language,
+}
+
+impl SortKey {
+    const ALL: [SortKey; 4] = [SortKey::Artist, SortKey::Title, SortKey::Genre, SortKey::Language];
+
+    fn name(self) -> &'static str {
+        match self {
+            SortKey::Artist => "artist",
+            SortKey::Title => "title",
+            SortKey::Genre => "genre",
+            SortKey::Language => "language",
+        }
+    }
+
+    /// The next sort key, going backwards if `forward` is false.
+    fn cycle(self, forward: bool) -> SortKey {
+        let index = SortKey::ALL.iter().position(|key| *key == self).unwrap_or(0);
+        let count = SortKey::ALL.len();
+        let next = if forward { index + 1 } else { index + count - 1 };
+        SortKey::ALL[next % count]
+    }
+}
+
+/// A song listed in the browser.
+enum Song {
+    Local(SongEntry),
+    Remote(SearchResult),
+}
+
+impl Song {
+    fn artist(&self) -> &str {
+        match self {
+            Song::Local(song) => &song.artist,
+            Song::Remote(song) => &song.artist,
+        }
+    }
+
+    fn title(&self) -> &str {
+        match self {
+            Song::Local(song) => &song.title,
+            Song::Remote(song) => &song.title,
+        }
+    }
+
+    fn genre(&self) -> Option<&str> {
+        match self {
+            Song::Local(song) => song.genre.as_deref(),
+            Song::Remote(song) => song.genre.as_deref(),
+        }
+    }
+
+    fn language(&self) -> Option<&str> {
+        match self {
+            Song::Local(song) => song.language.as_deref(),
+            Song::Remote(_) => None,
+        }
+    }
+
+    fn sort_field(&self, key: SortKey) -> String {
+        match key {
+            SortKey::Artist => self.artist(),
+            SortKey::Title => self.title(),
+            SortKey::Genre => self.genre().unwrap_or(""),
+            SortKey::Language => self.language().unwrap_or(""),
+        }
+        .to_lowercase()
+    }
+
+    /// The text the filter is matched against.
+    fn search_text(&self) -> String {
+        format!(
+            "{} {} {} {}",
+            self.artist(),
+            self.title(),
+            self.genre().unwrap_or(""),
+            self.language().unwrap_or("")
+        )
+    }
+
+    /// The metadata shown in the details pane.
+    fn details(&self) -> Vec<(&'static str, String)> {
+        let mut details = vec![
+            ("Title", self.title().to_string()),
+            ("Artist", self.artist().to_string()),
+        ];
+        match self {
+            Song::Local(song) => {
+                let optional = [
+                    ("Genre", song.genre.clone()),
+                    ("Language", song.language.clone()),
+                    ("Edition", song.edition.clone()),
+                    ("Year", song.year.map(|year| year.to_string())),
+                ];
+                details.extend(optional.into_iter().filter_map(|(name, value)| Some((name, value?))));
+                let duration = song.duration.round() as u32;
+                details.push(("Duration", format!("{}:{:02}", duration / 60, duration % 60)));
+                if song.duet {
+                    details.push(("Parts", "duet".to_string()));
+                }
+                details.push(("File", song.path.display().to_string()));
+                for file in song.missing_files.iter() {
+                    details.push(("Missing", file.clone()));
+                }
+            }
+            Song::Remote(song) => {
+                if let Some(genre) = song.genre.as_ref() {
+                    details.push(("Genre", genre.clone()));
+                }
+                details.push(("Server", server_interface::song_url(song)));
+            }
+        }
+        details
+    }
+}
+
+/// What to do after a key press.
+enum Action {
+    Continue,
+    SearchServer,
+    Play(Selection),
+    Quit,
+}
+
+/// The state of the browser.
+struct Browser {
+    songs: Vec<Song>,
+    /// The servers searched with Ctrl-R.
+    servers: Vec<String>,
+    filter: String,
+    sort_key: SortKey,
+    /// Indices of the songs matching the filter, in the order they are listed.
+    visible: Vec<usize>,
+    /// Index into `visible` of the selected song.
+    selected: usize,
+    /// Index into `visible` of the first listed song.
+    scroll: usize,
+    /// A message shown at the bottom of the screen.
+    status: String,
+    /// The preview of the selected song, if it is playing.
+    preview: Option<Preview>,
+    /// When to start the preview of the selected song.
+    preview_due: Option<Instant>,
+}
+
+impl Browser {
+    fn new(library: &Library, servers: &[String]) -> Browser {
+        let mut browser = Browser {
+            songs: library.songs.iter().cloned().map(Song::Local).collect(),
+            servers: servers.to_vec(),
+            filter: String::new(),
+            sort_key: SortKey::Artist,
+            visible: Vec::new(),
+            selected: 0,
+            scroll: 0,
+            status: format!("{} songs in the library", library.songs.len()),
+            preview: None,
+            preview_due: None,
+        };
+        browser.update_visible();
+        browser.schedule_preview();
+        browser
+    }
+
+    /// Stop the current preview and play the selected song soon.
+    ///
+    /// The preview is delayed so that scrolling through the list does not
+    /// load every song on the way.
+    fn schedule_preview(&mut self) {
+        self.preview = None;
+        self.preview_due = Some(Instant::now() + PREVIEW_DELAY);
+    }
+
+    /// Start a scheduled preview and fade the playing one.
+    ///
+    /// Returns whether the status changed.
+    fn update_preview(&mut self) -> bool {
+        if let Some(preview) = self.preview.as_mut() {
+            if !preview.update() {
+                self.preview = None;
+            }
+        }
+        match self.preview_due {
+            Some(due) if due <= Instant::now() => self.preview_due = None,
+            _ => return false,
+        }
+        // songs on the server have to be downloaded before they can be heard
+        let song = match self.selected_song() {
+            Some(Song::Local(song)) => song,
+            _ => return false,
+        };
+        let song_dir = song.path.parent().unwrap_or_else(|| Path::new(""));
+        let preview = get_url_content_provider(&song.audio, song_dir).and_then(|content_provider| {
+            let url = *content_provider.urls().first().chain_err(|| "no audio found for the song")?;
+            Preview::start(url, preview_start(song))
+        });
+        match preview {
+            Ok(preview) => {
+                self.preview = Some(preview);
+                false
+            }
+            Err(e) => {
+                self.status = format!("no preview: {}", e);
+                true
+            }
+        }
+    }
+
+    /// Filter and sort the songs again, e.g. after the filter changed.
+    fn update_visible(&mut self) {
+        let sort_key = self.sort_key;
+        let mut matches: Vec<(usize, i32, String)> = self
+            .songs
+            .iter()
+            .enumerate()
+            .filter_map(|(index, song)| {
+                // the server matched its songs already, the filter may contain filters like `artist:`
+                let score = match song {
+                    Song::Local(_) => fuzzy_score(&self.filter, &song.search_text())?,
+                    Song::Remote(_) => 0,
+                };
+                Some((index, score, song.sort_field(sort_key)))
+            })
+            .collect();
+        // the best matches first, ties are broken by the sort key
+        matches.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.2.cmp(&b.2)));
+        self.visible = matches.into_iter().map(|(index, _, _)| index).collect();
+        self.selected = 0;
+        self.scroll = 0;
+    }
+
+    fn selected_song(&self) -> Option<&Song> {
+        self.visible.get(self.selected).map(|index| &self.songs[*index])
+    }
+
+    /// Replace the songs found on the server by the given ones.
+    fn set_remote_songs(&mut self, results: Vec<SearchResult>) {
+        self.songs.retain(|song| matches!(song, Song::Local(_)));
+        self.status = format!("{} songs found on the server", results.len());
+        self.songs.extend(results.into_iter().map(Song::Remote));
+        self.update_visible();
+    }
+
+    fn move_selection(&mut self, offset: isize) {
+        let last = self.visible.len().saturating_sub(1) as isize;
+        self.selected = (self.selected as isize + offset).clamp(0, last) as usize;
+    }
+
+    fn handle_key(&mut self, key: Key, list_rows: usize) -> Action {
+        match key {
+            Key::Esc | Key::Ctrl('c') => return Action::Quit,
+            Key::Char('\n') => {
+                return match self.selected_song() {
+                    Some(Song::Local(song)) => Action::Play(Selection::Local(song.path.clone())),
+                    Some(Song::Remote(song)) => Action::Play(Selection::Remote(song.clone())),
+                    None => Action::Continue,
+                }
+            }
+            Key::Ctrl('r') => return Action::SearchServer,
+            Key::Up => self.move_selection(-1),
+            Key::Down => self.move_selection(1),
+            Key::PageUp => self.move_selection(-(list_rows as isize)),
+            Key::PageDown => self.move_selection(list_rows as isize),
+            Key::Home => self.move_selection(isize::MIN / 2),
+            Key::End => self.move_selection(isize::MAX / 2),
+            Key::Left | Key::Right => {
+                self.sort_key = self.sort_key.cycle(key == Key::Right);
+                self.update_visible();
+            }
+            Key::Backspace => {
+                self.filter.pop();
+                self.update_visible();
+            }
+            Key::Char(c) if !c.is_control() => {
+                self.filter.push(c);
+                self.update_visible();
+            }
+            _ => {}
+        }
+        Action::Continue
+    }
+
+    /// Keep the selected song within the listed rows.
+    fn scroll_to_selection(&mut self, list_rows: usize) {
+        if self.selected < self.scroll {
+            self.scroll = self.selected;
+        } else if list_rows > 0 && self.selected >= self.scroll + list_rows {
+            self.scroll = self.selected + 1 - list_rows;
+        }
+    }
+
+    fn render(&self, term_width: u16, term_height: u16) -> String {
+        let details_width = DETAILS_WIDTH.min(term_width / 2);
+        let list_width = term_width.saturating_sub(details_width + 2) as usize;
+        let list_rows = list_rows(term_height);
+
+        let mut output = format!(
+            "{}Filter: {}{}{}",
+            cursor::Goto(1, 1),
+            style::Bold,
+            fit(&self.filter, term_width.saturating_sub(8) as usize),
+            style::Reset
+        );
+        output.push_str(&format!(
+            "{}{}",
+            cursor::Goto(1, 2),
+            fit(
+                &format!(
+                    "Sorted by {} (left/right), Ctrl-R: search server, Enter: play, Esc: quit",
+                    self.sort_key.name()
+                ),
+                term_width as usize
+            )
+        ));
+
+        for (row, index) in self.visible.iter().skip(self.scroll).take(list_rows).enumerate() {
+            let song = &self.songs[*index];
+            let source = match song {
+                Song::Local(_) => "",
+                Song::Remote(_) => " [server]",
+            };
+            let text = fit(
+                &format!("{} - {}{}", song.artist(), song.title(), source),
+                list_width,
+            );
+            output.push_str(&format!("{}", cursor::Goto(1, HEADER_ROWS + 1 + row as u16)));
+            if self.scroll + row == self.selected {
+                output.push_str(&format!("{}{:width$}{}", style::Invert, text, style::Reset, width = list_width));
+            } else {
+                output.push_str(&text);
+            }
+        }
+
+        if let Some(song) = self.selected_song() {
+            let column = term_width.saturating_sub(details_width) + 1;
+            let value_width = (details_width as usize).saturating_sub(10);
+            for (row, (name, value)) in song.details().iter().enumerate().take(list_rows) {
+                output.push_str(&format!(
+                    "{}{}{:9}{} {}",
+                    cursor::Goto(column, HEADER_ROWS + 1 + row as u16),
+                    style::Bold,
+                    name,
+                    style::Reset,
+                    fit(value, value_width)
+                ));
+            }
+        }
+
+        output.push_str(&format!(
+            "{}{}",
+            cursor::Goto(1, term_height),
+            fit(&self.status, term_width as usize)
+        ));
+        output
+    }
+}
+
+/// Let the user pick a song.
+///
+/// Songs not in the library are searched on the given servers.
+/// Returns `None` if the user quit the browser.
+pub fn run(library: &Library, servers: &[String]) -> Result<Option<Selection>> {
+    let mut browser = Browser::new(library, servers);
+    let mut screen = AlternateScreen::from(
+        stdout()
+            .into_raw_mode()
+            .chain_err(|| "could not switch terminal to raw mode")?,
+    );
+    // don't block on input, previews have to be faded and started meanwhile
+    let mut keys = termion::async_stdin().keys();
+    let mut redraw = true;
+
+    let selection = loop {
+        let (term_width, term_height) =
+            termion::terminal_size().chain_err(|| "could not get terminal size")?;
+        let list_rows = list_rows(term_height);
+        if redraw {
+            browser.scroll_to_selection(list_rows);
+            write!(
+                screen,
+                "{}{}{}",
+                cursor::Hide,
+                clear::All,
+                browser.render(term_width, term_height)
+            )
+            .chain_err(|| "could not write to stdout")?;
+            screen.flush().chain_err(|| "could not flush stdout")?;
+            redraw = false;
+        }
+
+        redraw |= browser.update_preview();
+
+        let key = match keys.next() {
+            Some(key) => key.chain_err(|| "could not read from stdin")?,
+            None => {
+                thread::sleep(INPUT_POLL_INTERVAL);
+                continue;
+            }
+        };
+        redraw = true;
+        let selected = browser.visible.get(browser.selected).copied();
+        match browser.handle_key(key, list_rows) {
+            Action::Continue => {}
+            Action::Quit => break None,
+            Action::Play(selection) => break Some(selection),
+            Action::SearchServer => {
+                let query = match Query::parse(&browser.filter) {
+                    Ok(query) if query.is_empty() => {
+                        browser.status = "type a keyword to search the server for".to_string();
+                        continue;
+                    }
+                    Ok(query) => query,
+                    Err(e) => {
+                        browser.status = format!("invalid search: {}", e);
+                        continue;
+                    }
+                };
+                write!(
+                    screen,
+                    "{}{}searching the server...",
+                    cursor::Goto(1, term_height),
+                    clear::CurrentLine
+                )
+                .chain_err(|| "could not write to stdout")?;
+                screen.flush().chain_err(|| "could not flush stdout")?;
+                match server_interface::search_songs(&browser.servers, &query) {
+                    Ok(results) => browser.set_remote_songs(results),
+                    Err(e) => browser.status = format!("search failed: {}", e),
+                }
+            }
+        }
+        if browser.visible.get(browser.selected).copied() != selected {
+            browser.schedule_preview();
+        }
+    };
+
+    // stop the preview before the song is played
+    browser.preview = None;
+    write!(screen, "{}", cursor::Show).chain_err(|| "could not write to stdout")?;
+    Ok(selection)
+}
+
+/// Where the preview of a song starts.
+///
+/// Songs without `#PREVIEWSTART` are previewed from 30% into the song, which
+/// usually is past the intro and close to a chorus.
+fn preview_start(song: &SongEntry) -> Duration {
+    let start = song.preview_start.unwrap_or(song.duration * 0.3);
+    Duration::from_secs_f32(start.max(0.0))
+}
+
+/// The number of songs listed at once.
+fn list_rows(term_height: u16) -> usize {
+    // the status line and a gap below the list
+    term_height.saturating_sub(HEADER_ROWS + 2) as usize
+}
+
+/// Cut `text` to at most `width` characters.
+fn fit(text: &str, width: usize) -> String {
+    text.chars().take(width).collect()
+}
+
+/// How well `filter` matches `text`, `None` if it does not match at all.
+///
+/// The characters of the filter have to appear in `text` in the same order,
+/// but not necessarily next to each other.  Consecutive characters and
+/// matches at the start of words score higher.
+fn fuzzy_score(filter: &str, text: &str) -> Option<i32> {
+    let text: Vec<char> = text.chars().flat_map(char::to_lowercase).collect();
+    let mut score = 0;
+    let mut position = 0;
+    let mut last_match: Option<usize> = None;
+    for c in filter.chars().flat_map(char::to_lowercase) {
+        let found = position + text[position..].iter().position(|t| *t == c)?;
+        score += match last_match {
+            Some(last) if last + 1 == found => 3,
+            _ if found == 0 || text[found - 1] == ' ' => 2,
+            _ => 1,
+        };
+        last_match = Some(found);
+        position = found + 1;
+    }
+    Some(score)
+}
@@ -0,0 +1,249 @@
+//! Measures the delay between playing a sound and capturing it.
+//!
+//! A track of clicks is played and recorded at the same time.  The recording
+//! is cross-correlated with the clicks, placed where the reported playback
+//! position says they are.  The shift with the strongest correlation is the
+//! latency between the position the player sees and what it hears the
+//! singers sing.
+
+use crate::errors::*;
+
+use std::{
+    io::Write,
+    time::{Duration, Instant},
+};
+
+use tempfile::NamedTempFile;
+
+use crate::audio_input;
+use crate::capture::CaptureSettings;
+use crate::playback::{GStreamerPlayer, Player};
+
+/// When the clicks are played in ms, irregular so that no shift of the
+/// clicks lines up with other clicks.
+const CLICK_TIMES_MS: [usize; 8] = [500, 1200, 1600, 2500, 2800, 3700, 4000, 4900];
+const CLICK_LENGTH_MS: usize = 20;
+const CLICK_FREQUENCY: f32 = 1000.0;
+/// Silence after the last click.
+const TAIL_MS: usize = 500;
+/// The longest latency that can be measured.
+const MAX_LATENCY_MS: usize = 500;
+/// How far the correlation of the best shift has to stand out to be trusted.
+const MIN_PEAK_RATIO: f32 = 3.0;
+/// How many times louder than the fluctuations of the noise the clicks have
+/// to be recorded.
+const MIN_SIGNAL_TO_NOISE: f32 = 3.0;
+/// How long to wait for the click track to start playing.
+const START_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Play clicks and record them with the given capture settings.
+///
+/// Returns the latency in ms.
+pub fn measure_latency(settings: &CaptureSettings) -> Result<f32> {
+    let sample_rate = settings.sample_rate;
+    let clicks = click_track(sample_rate);
+    let track_ms = (clicks.len() as f32 * 1000.0 / sample_rate as f32) as usize;
+
+    let mut file = NamedTempFile::new().chain_err(|| "could not create temporary file")?;
+    write_wav(file.as_file_mut(), &clicks, sample_rate).chain_err(|| "could not write click track")?;
+    let url = glib::filename_to_uri(file.path(), None).chain_err(|| "could not convert click track to an uri")?;
+    let mut player = GStreamerPlayer::new();
+    player.load(&url)?;
+
+    let mut input = audio_input::open(settings.default_device.as_deref(), 1, settings)?;
+    input.start()?;
+    let recording_started = Instant::now();
+    player.play().chain_err(|| "could not play click track")?;
+
+    let mut recording: Vec<f32> = Vec::new();
+    // when the track started according to the reported position, in ms after the recording started
+    let mut track_starts: Vec<f32> = Vec::new();
+    let track_start = loop {
+        let block = input.next_block()?.chain_err(|| "the audio input ended during the calibration")?;
+        recording.extend_from_slice(&block.channels[0]);
+
+        let now = recording_started.elapsed();
+        if let Some(position) = player.position() {
+            if position > 0.0 {
+                track_starts.push(now.as_secs_f32() * 1000.0 - position);
+            }
+        }
+
+        match median(&mut track_starts) {
+            Some(track_start) => {
+                let recorded_ms = recording.len() as f32 * 1000.0 / sample_rate as f32;
+                if recorded_ms > track_start + (track_ms + MAX_LATENCY_MS) as f32 {
+                    break track_start;
+                }
+            }
+            None if now > START_TIMEOUT => {
+                return Err("the click track did not start playing".into());
+            }
+            None => {}
+        }
+    };
+    drop(player);
+
+    find_latency(&envelope(&clicks, sample_rate), &envelope(&recording, sample_rate), track_start)
+        .chain_err(|| "could not hear the clicks, turn up the volume or move the microphone closer to the speakers")
+}
+
+/// Short beeps at `CLICK_TIMES_MS`.
+fn click_track(sample_rate: u32) -> Vec<f32> {
+    let samples_per_ms = sample_rate as usize / 1000;
+    let length = CLICK_TIMES_MS[CLICK_TIMES_MS.len() - 1] + CLICK_LENGTH_MS + TAIL_MS;
+    let mut samples = vec![0.0; length * samples_per_ms];
+    for click_ms in CLICK_TIMES_MS.iter() {
+        let start = click_ms * samples_per_ms;
+        for i in 0..CLICK_LENGTH_MS * samples_per_ms {
+            let t = i as f32 / sample_rate as f32;
+            samples[start + i] = (2.0 * std::f32::consts::PI * CLICK_FREQUENCY * t).sin() * 0.8;
+        }
+    }
+    samples
+}
+
+/// Write mono samples as 16 bit PCM WAV file.
+fn write_wav(writer: &mut impl Write, samples: &[f32], sample_rate: u32) -> std::io::Result<()> {
+    let data_length = samples.len() as u32 * 2;
+    writer.write_all(b"RIFF")?;
+    writer.write_all(&(36 + data_length).to_le_bytes())?;
+    writer.write_all(b"WAVEfmt ")?;
+    writer.write_all(&16u32.to_le_bytes())?;
+    // PCM, one channel
+    writer.write_all(&1u16.to_le_bytes())?;
+    writer.write_all(&1u16.to_le_bytes())?;
+    writer.write_all(&sample_rate.to_le_bytes())?;
+    writer.write_all(&(sample_rate * 2).to_le_bytes())?;
+    // bytes per frame and bits per sample
+    writer.write_all(&2u16.to_le_bytes())?;
+    writer.write_all(&16u16.to_le_bytes())?;
+    writer.write_all(b"data")?;
+    writer.write_all(&data_length.to_le_bytes())?;
+    for sample in samples {
+        let sample = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+        writer.write_all(&sample.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+/// The mean absolute level of every millisecond.
+fn envelope(samples: &[f32], sample_rate: u32) -> Vec<f32> {
+    let samples_per_ms = (sample_rate as usize / 1000).max(1);
+    samples
+        .chunks(samples_per_ms)
+        .map(|chunk| chunk.iter().map(|x| x.abs()).sum::<f32>() / chunk.len() as f32)
+        .collect()
+}
+
+/// Find the shift of the recording that matches the clicks best.
+///
+/// Both are envelopes with one value per ms, the track started `track_start`
+/// ms after the recording according to the reported position.
+fn find_latency(clicks: &[f32], recording: &[f32], track_start: f32) -> Result<f32> {
+    // remove the noise floor, so that only the clicks correlate, the few
+    // clicks in the recording hardly move the median
+    let noise = median(&mut recording.to_vec()).chain_err(|| "nothing was recorded")?;
+    let mut deviations: Vec<f32> = recording.iter().map(|level| (level - noise).abs()).collect();
+    let noise_spread = median(&mut deviations).unwrap_or(0.0);
+    let track_start = track_start.round() as isize;
+
+    let correlations: Vec<f32> = (0..=MAX_LATENCY_MS)
+        .map(|latency| {
+            clicks
+                .iter()
+                .enumerate()
+                .filter_map(|(ms, click)| {
+                    let recorded_ms = ms as isize + track_start + latency as isize;
+                    let level = recording.get(usize::try_from(recorded_ms).ok()?)?;
+                    Some(click * (level - noise))
+                })
+                .sum()
+        })
+        .collect();
+
+    let (latency, peak) = correlations
+        .iter()
+        .enumerate()
+        .max_by(|a, b| a.1.partial_cmp(b.1).unwrap_or(std::cmp::Ordering::Equal))
+        .chain_err(|| "no correlation")?;
+    let mean = correlations.iter().map(|c| c.abs()).sum::<f32>() / correlations.len() as f32;
+    if *peak <= 0.0 || *peak < mean * MIN_PEAK_RATIO {
+        return Err("the clicks do not stand out of the recording".into());
+    }
+    // how loud the loudest click was recorded, judged by the best shift
+    let click_energy: f32 = clicks.iter().map(|click| click * click).sum();
+    let loudest_click = clicks.iter().cloned().fold(0.0, f32::max);
+    if peak / click_energy * loudest_click < noise_spread * MIN_SIGNAL_TO_NOISE {
+        return Err("the clicks are not louder than the noise of the recording".into());
+    }
+    Ok(latency as f32)
+}
+
+/// The median of the values, sorting them.
+fn median(values: &mut [f32]) -> Option<f32> {
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    values.get(values.len() / 2).copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 8_000;
+
+    /// Quiet noise that is the same in every run.
+    fn noise(len: usize, level: f32) -> Vec<f32> {
+        let mut state: u32 = 12_345;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                ((state >> 16) as f32 / 32_768.0 - 1.0) * level
+            })
+            .collect()
+    }
+
+    /// A recording in which the clicks are heard `delay_ms` after the start.
+    fn recording(clicks: &[f32], delay_ms: usize) -> Vec<f32> {
+        let samples_per_ms = SAMPLE_RATE as usize / 1000;
+        let mut recording = noise(clicks.len() + (delay_ms + MAX_LATENCY_MS) * samples_per_ms, 0.02);
+        for (i, click) in clicks.iter().enumerate() {
+            recording[delay_ms * samples_per_ms + i] += click * 0.3;
+        }
+        recording
+    }
+
+    #[test]
+    fn averages_the_level_of_every_millisecond() {
+        let samples = [0.5, -0.5, 0.25, -0.25, 0.0, 0.0, 0.0, 0.0, 1.0, -1.0, 1.0, -1.0];
+        assert_eq!(envelope(&samples, 4_000), vec![0.375, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn finds_the_delay_of_the_recorded_clicks() {
+        let clicks = click_track(SAMPLE_RATE);
+        let track_start = 120.0;
+        let latency = 137;
+        let recording = recording(&clicks, track_start as usize + latency);
+
+        let found = find_latency(
+            &envelope(&clicks, SAMPLE_RATE),
+            &envelope(&recording, SAMPLE_RATE),
+            track_start,
+        )
+        .unwrap();
+        assert!((found - latency as f32).abs() <= 1.0, "found {} ms", found);
+    }
+
+    #[test]
+    fn fails_without_clicks_in_the_recording() {
+        let clicks = click_track(SAMPLE_RATE);
+        let recording = noise(clicks.len() * 2, 0.02);
+        let result = find_latency(
+            &envelope(&clicks, SAMPLE_RATE),
+            &envelope(&recording, SAMPLE_RATE),
+            120.0,
+        );
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,275 @@
+//! Captures the microphones of the singers and detects their pitch.
+
+use crate::errors::*;
+
+use std::{
+    str::FromStr,
+    sync::{Arc, Mutex},
+    thread,
+    time::Instant,
+};
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::audio_input::{self, AudioInput, Backend};
+use crate::pitch::{self, PitchReading};
+
+/// Default sample rate to capture with.
+pub const SAMPLE_RATE: u32 = 44_100;
+/// Default number of frames to detect a pitch in.
+pub const FRAMES: usize = 2048;
+/// Default minimal level of the captured samples to detect a pitch.
+const RMS_THRESHOLD: f32 = 0.03;
+/// Default minimal clarity of the captured samples to detect a pitch.
+const CLARITY_THRESHOLD: f64 = 0.5;
+
+/// The default GStreamer source element.
+const GSTREAMER_SOURCE: &str = "autoaudiosrc";
+
+/// What the capture thread of a microphone has to tell.
+#[derive(Clone, Debug, Default)]
+pub struct InputState {
+    /// The latest pitch reading.
+    pub reading: Option<PitchReading>,
+    /// Why capturing stopped.
+    pub error: Option<String>,
+}
+
+/// The state of a microphone, shared with the capture thread.
+pub type SharedReading = Arc<Mutex<InputState>>;
+
+/// How capture devices are opened and pitches detected.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct CaptureSettings {
+    /// Where the audio comes from.
+    pub backend: Backend,
+    /// The source element of the GStreamer backend, e.g. `pulsesrc`.
+    pub gstreamer_source: String,
+    /// The device used for microphones without a device, `None` for the
+    /// default device of the system.
+    #[serde(rename = "input_device")]
+    pub default_device: Option<String>,
+    pub sample_rate: u32,
+    /// The number of frames analyzed at once.
+    #[serde(rename = "buffer_frames")]
+    pub frames: usize,
+    /// Minimal level of the captured samples to detect a pitch.
+    pub rms_threshold: f32,
+    /// Minimal clarity of the captured samples to detect a pitch.
+    pub clarity_threshold: f64,
+}
+
+impl Default for CaptureSettings {
+    fn default() -> CaptureSettings {
+        CaptureSettings {
+            backend: Backend::OpenAl,
+            gstreamer_source: GSTREAMER_SOURCE.to_string(),
+            default_device: None,
+            sample_rate: SAMPLE_RATE,
+            frames: FRAMES,
+            rms_threshold: RMS_THRESHOLD,
+            clarity_threshold: CLARITY_THRESHOLD,
+        }
+    }
+}
+
+/// A capture device, or a single channel of a multi-channel capture device.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Microphone {
+    /// The name of the capture device, `None` for the default device.
+    pub device: Option<String>,
+    /// The channel to use, `None` to mix all channels.
+    pub channel: Option<usize>,
+}
+
+impl FromStr for Microphone {
+    type Err = String;
+
+    /// Parse `DEVICE` or `DEVICE#CHANNEL`, where `default` names the default device.
+    ///
+    /// Device names often contain colons, like the ALSA device `hw:1`, so the
+    /// channel is separated by `#`.
+    fn from_str(s: &str) -> std::result::Result<Microphone, String> {
+        let (device, channel) = match s.rsplit_once('#') {
+            Some((device, channel)) => {
+                let channel = channel
+                    .parse::<usize>()
+                    .map_err(|_| format!("invalid channel \"{}\" of microphone {}", channel, s))?;
+                (device, Some(channel))
+            }
+            None => (s, None),
+        };
+        let device = match device {
+            "" | "default" => None,
+            device => Some(device.to_string()),
+        };
+        Ok(Microphone { device, channel })
+    }
+}
+
+/// Open the given microphones and capture them in background threads.
+///
+/// Returns the shared state of every microphone in the same order.
+/// Microphones on the same device share one capture thread.  If capturing
+/// fails, the error is left in the state of all its microphones.
+pub fn start(microphones: &[Microphone], settings: &CaptureSettings) -> Result<Vec<SharedReading>> {
+    check_frames(settings)?;
+
+    let readings: Vec<SharedReading> = microphones
+        .iter()
+        .map(|_| Arc::new(Mutex::new(InputState::default())))
+        .collect();
+
+    // microphones without a device use the configured default device
+    let microphones: Vec<Microphone> = microphones
+        .iter()
+        .map(|microphone| Microphone {
+            device: microphone
+                .device
+                .clone()
+                .or_else(|| settings.default_device.clone()),
+            channel: microphone.channel,
+        })
+        .collect();
+
+    let mut devices: Vec<&Option<String>> = Vec::new();
+    for microphone in microphones.iter() {
+        if !devices.contains(&&microphone.device) {
+            devices.push(&microphone.device);
+        }
+    }
+
+    // open and check every device before capturing any of them
+    let mut inputs = Vec::new();
+    for device in devices {
+        // the microphones of this device and where their readings go
+        let users: Vec<(Option<usize>, SharedReading)> = microphones
+            .iter()
+            .zip(readings.iter())
+            .filter(|(microphone, _)| &microphone.device == device)
+            .map(|(microphone, reading)| (microphone.channel, reading.clone()))
+            .collect();
+        // enough channels for the highest one used, one to mix them all
+        let channels = users
+            .iter()
+            .filter_map(|(channel, _)| channel.map(|channel| channel + 1))
+            .max()
+            .unwrap_or(1);
+
+        let input = audio_input::open(device.as_deref(), channels, settings)?;
+        for (channel, _) in users.iter() {
+            if let Some(channel) = channel {
+                if *channel >= input.channels() {
+                    return Err(format!(
+                        "input device {} has no channel {}",
+                        device.as_deref().unwrap_or("default"),
+                        channel
+                    ).into());
+                }
+            }
+        }
+        inputs.push((input, users));
+    }
+
+    for (mut input, users) in inputs {
+        let settings = settings.clone();
+        thread::spawn(move || {
+            let error = match capture_loop(input.as_mut(), &users, &settings) {
+                Ok(()) => "the audio input has ended".to_string(),
+                Err(e) => e.iter().map(|e| e.to_string()).collect::<Vec<_>>().join(": "),
+            };
+            for (_, reading) in users.iter() {
+                let mut state = reading.lock().unwrap();
+                state.reading = None;
+                state.error = Some(error.clone());
+            }
+        });
+    }
+
+    Ok(readings)
+}
+
+/// Detect the pitches of the microphones in every block of the input until it ends.
+fn capture_loop(
+    input: &mut dyn AudioInput,
+    users: &[(Option<usize>, SharedReading)],
+    settings: &CaptureSettings,
+) -> Result<()> {
+    input.start()?;
+    while let Some(block) = input.next_block()? {
+        for (channel, reading) in users.iter() {
+            let samples = match channel {
+                Some(channel) => block.channels[*channel].clone(),
+                None => mix_channels(&block.channels),
+            };
+            reading.lock().unwrap().reading =
+                detect_pitch(&samples, input.sample_rate(), settings, block.timestamp);
+        }
+    }
+    Ok(())
+}
+
+/// Make sure the buffer is long enough to detect the lowest pitches.
+pub fn check_frames(settings: &CaptureSettings) -> Result<()> {
+    let min_frames = pitch::min_samples(settings.sample_rate as f64);
+    if settings.frames < min_frames {
+        return Err(format!(
+            "a buffer of {} frames is too short to detect pitches at {} Hz, use at least {}",
+            settings.frames, settings.sample_rate, min_frames
+        ).into());
+    }
+    Ok(())
+}
+
+/// Mix the channels into one by averaging them.
+pub fn mix_channels(channels: &[Vec<f32>]) -> Vec<f32> {
+    let count = channels.len() as f32;
+    (0..channels[0].len())
+        .map(|i| channels.iter().map(|channel| channel[i]).sum::<f32>() / count)
+        .collect()
+}
+
+/// Detect the pitch of the samples if they are loud and clear enough.
+pub fn detect_pitch(
+    samples: &[f32],
+    sample_rate: u32,
+    settings: &CaptureSettings,
+    timestamp: Instant,
+) -> Option<PitchReading> {
+    PitchReading::from_samples(samples, sample_rate as f64, timestamp)
+        .filter(|reading| reading.rms > settings.rms_threshold && reading.clarity > settings.clarity_threshold)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn microphone(device: Option<&str>, channel: Option<usize>) -> Microphone {
+        Microphone {
+            device: device.map(String::from),
+            channel,
+        }
+    }
+
+    #[test]
+    fn parses_devices_with_colons_and_channels() {
+        assert_eq!("default".parse(), Ok(microphone(None, None)));
+        assert_eq!("default#1".parse(), Ok(microphone(None, Some(1))));
+        assert_eq!("#0".parse(), Ok(microphone(None, Some(0))));
+        // ALSA names end in numbers after a colon
+        assert_eq!("hw:1".parse(), Ok(microphone(Some("hw:1"), None)));
+        assert_eq!("plughw:0".parse(), Ok(microphone(Some("plughw:0"), None)));
+        assert_eq!("hw:1,0#3".parse(), Ok(microphone(Some("hw:1,0"), Some(3))));
+        assert!("hw:1#left".parse::<Microphone>().is_err());
+        assert!("hw:1#".parse::<Microphone>().is_err());
+    }
+
+    #[test]
+    fn needs_enough_frames_for_the_lowest_pitches() {
+        let mut settings = CaptureSettings::default();
+        assert!(check_frames(&settings).is_ok());
+        settings.frames = 16;
+        assert!(check_frames(&settings).is_err());
+    }
+}
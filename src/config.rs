@@ -0,0 +1,205 @@
+//! The configuration file of the player.
+//!
+//! The file is written in TOML and lives in the user's config directory,
+//! command line arguments override its values.  Everything missing in the
+//! file has a default, so an empty file is a valid config.
+
+use crate::errors::*;
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use colored::Color;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::capture::CaptureSettings;
+use crate::score::Difficulty;
+
+/// The environment variable listing the song servers, separated by commas.
+pub const SERVERS_VARIABLE: &str = "ASCII_STAR_SERVERS";
+
+/// The settings kept between runs.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Config {
+    /// How exact notes have to be sung: `easy`, `medium` or `hard`.
+    pub difficulty: Difficulty,
+    /// The delay between playing a sound and capturing it in ms, see `--calibrate`.
+    pub latency_ms: f32,
+    /// The song servers to search, all at once.
+    pub servers: Vec<String>,
+    /// The directories to search for songs.
+    pub library: Vec<PathBuf>,
+    /// Where songs from the servers are downloaded to, defaults to the user's cache directory.
+    pub download_dir: Option<PathBuf>,
+    pub capture: CaptureSettings,
+    pub theme: ThemeConfig,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            difficulty: Difficulty::Medium,
+            latency_ms: 0.0,
+            servers: vec!["http://localhost:8080".to_string()],
+            library: vec![PathBuf::from("songs")],
+            download_dir: None,
+            capture: CaptureSettings::default(),
+            theme: ThemeConfig::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Read the config file, a missing file gives the default config.
+    pub fn load(path: &Path) -> Result<Config> {
+        if !path.exists() {
+            return Ok(Config::default());
+        }
+        let content = fs::read_to_string(path)
+            .chain_err(|| format!("could not read config file {}", path.display()))?;
+        toml::from_str(&content).chain_err(|| format!("invalid config file {}", path.display()))
+    }
+
+    /// Write the config file.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .chain_err(|| format!("could not create directory {}", dir.display()))?;
+        }
+        fs::write(path, self.to_toml()?)
+            .chain_err(|| format!("could not write config file {}", path.display()))
+    }
+
+    /// The config in the format of the config file.
+    pub fn to_toml(&self) -> Result<String> {
+        toml::to_string(self).chain_err(|| "could not serialize config")
+    }
+}
+
+/// The colors of the screen by name, like `yellow` or `bright blue`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ThemeConfig {
+    pub regular_note: String,
+    pub regular_note_sung: String,
+    pub golden_note: String,
+    pub golden_note_sung: String,
+    pub freestyle_note: String,
+    pub freestyle_note_sung: String,
+    /// The colors that tell the singers apart, assigned in order.
+    pub singers: Vec<String>,
+}
+
+impl Default for ThemeConfig {
+    fn default() -> ThemeConfig {
+        ThemeConfig {
+            regular_note: "bright blue".to_string(),
+            regular_note_sung: "white".to_string(),
+            golden_note: "yellow".to_string(),
+            golden_note_sung: "bright yellow".to_string(),
+            freestyle_note: "red".to_string(),
+            freestyle_note_sung: "bright red".to_string(),
+            singers: ["bright green", "bright magenta", "bright cyan", "magenta"]
+                .iter()
+                .map(|color| color.to_string())
+                .collect(),
+        }
+    }
+}
+
+/// The colors of the screen.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Theme {
+    pub regular_note: Color,
+    pub regular_note_sung: Color,
+    pub golden_note: Color,
+    pub golden_note_sung: Color,
+    pub freestyle_note: Color,
+    pub freestyle_note_sung: Color,
+    /// The colors that tell the singers apart, never empty.
+    pub singers: Vec<Color>,
+}
+
+impl Theme {
+    /// Look up the colors named in the config.
+    pub fn from_config(config: &ThemeConfig) -> Result<Theme> {
+        let singers = config
+            .singers
+            .iter()
+            .map(|name| parse_color(name))
+            .collect::<Result<Vec<_>>>()?;
+        if singers.is_empty() {
+            return Err("the theme needs at least one singer color".into());
+        }
+        Ok(Theme {
+            regular_note: parse_color(&config.regular_note)?,
+            regular_note_sung: parse_color(&config.regular_note_sung)?,
+            golden_note: parse_color(&config.golden_note)?,
+            golden_note_sung: parse_color(&config.golden_note_sung)?,
+            freestyle_note: parse_color(&config.freestyle_note)?,
+            freestyle_note_sung: parse_color(&config.freestyle_note_sung)?,
+            singers,
+        })
+    }
+
+    /// The color of the `index`th singer.
+    pub fn singer_color(&self, index: usize) -> Color {
+        self.singers[index % self.singers.len()]
+    }
+}
+
+fn parse_color(name: &str) -> Result<Color> {
+    name.parse()
+        .map_err(|_| format!("unknown color \"{}\", use names like \"yellow\" or \"bright blue\"", name).into())
+}
+
+/// The default place of the config file, inside the user's config directory.
+pub fn default_path() -> Option<PathBuf> {
+    Some(user_dir("XDG_CONFIG_HOME", ".config")?.join("ascii-star").join("config.toml"))
+}
+
+/// A directory of the user following the XDG base directory specification.
+///
+/// `fallback` is the directory relative to the home directory used if the
+/// environment variable is not set.
+pub fn user_dir(variable: &str, fallback: &str) -> Option<PathBuf> {
+    match env::var_os(variable) {
+        Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir)),
+        _ => Some(PathBuf::from(env::var_os("HOME")?).join(fallback)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_unknown_difficulties_when_loading() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        fs::write(file.path(), "difficulty = \"hard\"\n").unwrap();
+        assert_eq!(Config::load(file.path()).unwrap().difficulty, Difficulty::Hard);
+
+        fs::write(file.path(), "difficulty = \"impossible\"\n").unwrap();
+        assert!(Config::load(file.path()).is_err());
+    }
+
+    #[test]
+    fn rejects_negative_buffer_frames() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        fs::write(file.path(), "[capture]\nbuffer_frames = 4096\n").unwrap();
+        assert_eq!(Config::load(file.path()).unwrap().capture.frames, 4096);
+
+        fs::write(file.path(), "[capture]\nbuffer_frames = -2048\n").unwrap();
+        assert!(Config::load(file.path()).is_err());
+    }
+
+    #[test]
+    fn writes_what_it_reads() {
+        let config = Config::default();
+        let read: Config = toml::from_str(&config.to_toml().unwrap()).unwrap();
+        assert_eq!(read.to_toml().unwrap(), config.to_toml().unwrap());
+    }
+}
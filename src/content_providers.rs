@@ -2,6 +2,13 @@
 
 mod youtube_dl;
 
+use std::{
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+use crate::errors::*;
+
 use self::youtube_dl::YtDlContentProvider;
 
 /// A content provider that uses URLs to provide content.
@@ -11,18 +18,47 @@ pub trait UrlContentProvider {
 }
 
 /// Returns the fitting content provider for the given path.
-pub fn get_url_content_provider(url: &str) -> Box<dyn UrlContentProvider> {
-    if url.starts_with("file://") {
+///
+/// Local paths are relative to `song_dir`, the directory of the song file.
+pub fn get_url_content_provider(url: &str, song_dir: &Path) -> Result<Box<dyn UrlContentProvider>> {
+    Ok(if url.starts_with("file://") {
         Box::new(SimpleURLProvider::from_url(url))
     } else if url.starts_with("http://") || url.starts_with("https://") {
         if url.contains("youtu.be") || url.contains("youtube") {
-            Box::new(YtDlContentProvider::new(url).unwrap())
+            Box::new(YtDlContentProvider::new(url).chain_err(|| format!("could not find the audio of {}", url))?)
         } else {
             Box::new(SimpleURLProvider::from_url(url))
         }
     } else {
-        Box::new(SimpleURLProvider::from_local_path(url))
+        Box::new(SimpleURLProvider::from_local_path(&resolve_local_path(url, song_dir)?)?)
+    })
+}
+
+/// Resolve a path of the song file against the directory of the song file.
+///
+/// Returns an absolute path, or an error if the file does not exist.
+pub fn resolve_local_path(path: &str, song_dir: &Path) -> Result<PathBuf> {
+    let full_path = song_dir.join(path);
+    if !full_path.is_file() {
+        return Err(format!("file \"{}\" of the song does not exist", full_path.display()).into());
+    }
+    full_path
+        .canonicalize()
+        .chain_err(|| format!("could not resolve \"{}\"", full_path.display()))
+}
+
+/// The `file://` uri of an absolute path, percent-encoding what is not
+/// allowed in uris like `glib::filename_to_uri` does.
+fn file_uri(path: &Path) -> String {
+    let mut uri = String::from("file://");
+    for byte in path.as_os_str().as_bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~/!$&'()*+,;=:@".contains(byte) {
+            uri.push(*byte as char);
+        } else {
+            uri.push_str(&format!("%{:02X}", byte));
+        }
     }
+    uri
 }
 
 /// Represents a local file to be played.
@@ -39,14 +75,14 @@ impl SimpleURLProvider {
         }
     }
 
-    /// Create a new simple url provider for a local file.
-    fn from_local_path(path: &str) -> SimpleURLProvider {
-        let mut url = String::from("file://");
-        url.push_str(path);
-
-        SimpleURLProvider {
-            url
+    /// Create a new simple url provider for a local file with an absolute path.
+    fn from_local_path(path: &Path) -> Result<SimpleURLProvider> {
+        if !path.is_absolute() {
+            return Err(format!("could not convert \"{}\" to an uri, it is relative", path.display()).into());
         }
+        Ok(SimpleURLProvider {
+            url: file_uri(path)
+        })
     }
 }
 
@@ -55,3 +91,61 @@ impl UrlContentProvider for SimpleURLProvider {
         vec![&self.url]
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    #[test]
+    fn resolves_paths_relative_to_the_song_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let song_dir = dir.path().join("Pornophonique - Space Invaders");
+        fs::create_dir_all(song_dir.join("covers")).unwrap();
+        fs::write(song_dir.join("song.mp3"), "").unwrap();
+        fs::write(song_dir.join("covers").join("front.jpg"), "").unwrap();
+
+        let song_dir_path = song_dir.canonicalize().unwrap();
+        assert_eq!(resolve_local_path("song.mp3", &song_dir).unwrap(), song_dir_path.join("song.mp3"));
+        assert_eq!(
+            resolve_local_path("covers/front.jpg", &song_dir).unwrap(),
+            song_dir_path.join("covers").join("front.jpg")
+        );
+    }
+
+    #[test]
+    fn reports_missing_files_of_the_song() {
+        let dir = tempfile::tempdir().unwrap();
+        let error = resolve_local_path("missing.mp3", dir.path()).unwrap_err().to_string();
+        assert!(error.contains("missing.mp3") && error.contains("does not exist"), "{}", error);
+        // directories are no files
+        fs::create_dir(dir.path().join("audio")).unwrap();
+        assert!(resolve_local_path("audio", dir.path()).is_err());
+        assert!(get_url_content_provider("missing.mp3", dir.path()).is_err());
+    }
+
+    #[test]
+    fn encodes_spaces_brackets_and_unicode_in_uris() {
+        assert_eq!(
+            file_uri(Path::new("/songs/Pornophonique - Space Invaders [CO].jpg")),
+            "file:///songs/Pornophonique%20-%20Space%20Invaders%20%5BCO%5D.jpg"
+        );
+        assert_eq!(file_uri(Path::new("/songs/Beyoncé/100% #1?.mp3")), "file:///songs/Beyonc%C3%A9/100%25%20%231%3F.mp3");
+        assert_eq!(file_uri(Path::new("/songs/AC,DC (live)/a+b.mp3")), "file:///songs/AC,DC%20(live)/a+b.mp3");
+    }
+
+    #[test]
+    fn plays_local_files_by_their_uri() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("Space Invaders [CO].mp3"), "").unwrap();
+        let provider = get_url_content_provider("Space Invaders [CO].mp3", dir.path()).unwrap();
+        let expected = file_uri(&dir.path().canonicalize().unwrap().join("Space Invaders [CO].mp3"));
+        assert!(expected.ends_with("/Space%20Invaders%20%5BCO%5D.mp3"));
+        assert_eq!(provider.urls(), vec![expected.as_str()]);
+
+        // urls are played as they are
+        let provider = get_url_content_provider("http://localhost:8080/song.mp3", dir.path()).unwrap();
+        assert_eq!(provider.urls(), vec!["http://localhost:8080/song.mp3"]);
+    }
+}
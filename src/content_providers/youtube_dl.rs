@@ -1,36 +1,30 @@
 use youtube_dl::{YoutubeDl, YoutubeDlOutput};
 
-use log::warn;
+use log::info;
 
 use super::UrlContentProvider;
+use crate::errors::*;
 
 pub struct YtDlContentProvider {
     urls: Vec<String>,
 }
 
 impl YtDlContentProvider {
-    pub fn new(url: &str) -> Option<YtDlContentProvider> {
-        let info = YoutubeDl::new(url).run().unwrap();
+    pub fn new(url: &str) -> Result<YtDlContentProvider> {
+        let info = YoutubeDl::new(url).run().chain_err(|| "youtube-dl failed")?;
 
         match info {
             YoutubeDlOutput::SingleVideo(video) => {
-                if let Some(formats) = video.formats {
-                    let urls = formats
-                        .into_iter()
-                        .filter(|f| f.acodec.is_some() && f.vcodec.is_none() && f.url.is_some())
-                        .map(|f| f.url)
-                        .flatten()
-                        .collect::<Vec<_>>();
-                    println!("Found urls: {:?}", urls);
-                    Some(YtDlContentProvider { urls })
-                } else {
-                    None
-                }
-            }
-            YoutubeDlOutput::Playlist(_playlist) => {
-                warn!("Playlists are currently not supported");
-                None
+                let formats = video.formats.chain_err(|| "the video has no formats")?;
+                let urls = formats
+                    .into_iter()
+                    .filter(|f| f.acodec.is_some() && f.vcodec.is_none())
+                    .filter_map(|f| f.url)
+                    .collect::<Vec<_>>();
+                info!("Found urls: {:?}", urls);
+                Ok(YtDlContentProvider { urls })
             }
+            YoutubeDlOutput::Playlist(_playlist) => Err("playlists are currently not supported".into()),
         }
     }
 }
@@ -0,0 +1,407 @@
+//! Downloads songs from the servers with all their files, to play them
+//! offline later.
+//!
+//! Every song gets its own directory in the download directory, holding the
+//! song file and the files it references just like on the server.  Files are
+//! downloaded to `<name>.part` first and renamed once they are complete, so
+//! an interrupted download is resumed the next time.
+
+use crate::errors::*;
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    path::{Component, Path, PathBuf},
+    time::Duration,
+};
+
+use log::warn;
+use reqwest::{
+    blocking::{Client, Response},
+    header::RANGE,
+    StatusCode, Url,
+};
+use sha2::{Digest, Sha256};
+
+use crate::config::{self, Config};
+use crate::server_interface::{self, SearchResult};
+use crate::song;
+
+/// The header servers may send with the SHA-256 hash of a file, in hex.
+const CHECKSUM_HEADER: &str = "x-checksum-sha256";
+/// How long to wait for a server to answer.
+const TIMEOUT: Duration = Duration::from_secs(30);
+/// Width of the progress bar in characters.
+const PROGRESS_WIDTH: usize = 30;
+
+/// Where downloaded songs are kept, the configured directory or the default
+/// one inside the user's cache directory.
+pub fn directory(config: &Config) -> Option<PathBuf> {
+    config
+        .download_dir
+        .clone()
+        .or_else(|| Some(config::user_dir("XDG_CACHE_HOME", ".cache")?.join("ascii-star").join("songs")))
+}
+
+/// Download a song with its audio, cover, background and video into
+/// `download_dir`, skipping files downloaded before.
+///
+/// `progress` is called while every file is downloaded.  Returns the path of
+/// the downloaded song file.
+pub fn download_song(song: &SearchResult, download_dir: &Path, progress: &mut dyn FnMut(&Progress)) -> Result<PathBuf> {
+    let client = Client::builder()
+        .timeout(TIMEOUT)
+        .build()
+        .chain_err(|| "could not create http client")?;
+
+    let (remote_dir, file_name) = match song.path.rsplit_once('/') {
+        Some((remote_dir, file_name)) => (remote_dir, file_name),
+        None => ("", song.path.as_str()),
+    };
+    let song_dir = download_dir.join(directory_name(song));
+    let song_path = song_dir.join(checked_path(file_name)?);
+    download_file(&client, server_interface::file_url(&song.server, &song.path)?, &song_path, progress)
+        .chain_err(|| "could not download the song file")?;
+
+    let header = song::load(&song_path)
+        .chain_err(|| "could not parse the downloaded song file")?
+        .header;
+    let mut download_referenced = |file: &str| -> Result<()> {
+        // streams like YouTube videos are not downloaded
+        if file.contains("://") {
+            return Ok(());
+        }
+        let remote_path = if remote_dir.is_empty() {
+            file.to_string()
+        } else {
+            format!("{}/{}", remote_dir, file)
+        };
+        checked_path(file)
+            .and_then(|path| {
+                let url = server_interface::file_url(&song.server, &remote_path)?;
+                download_file(&client, url, &song_dir.join(path), &mut *progress)
+            })
+            .chain_err(|| format!("could not download \"{}\"", file))
+    };
+
+    let audio = download_referenced(&header.audio_path);
+    let video = header.video_path.as_deref().map(&mut download_referenced);
+    for image in [&header.cover_path, &header.background_image_path].iter().copied().flatten() {
+        if let Err(e) = download_referenced(image) {
+            warn!("{}", describe(&e));
+        }
+    }
+    match (audio, video) {
+        (Ok(()), Some(Err(e))) => warn!("{}", describe(&e)),
+        // the video is played if there is no audio
+        (Err(e), Some(Ok(()))) => warn!("{}", describe(&e)),
+        (Err(e), _) => return Err(e),
+        _ => {}
+    }
+    Ok(song_path)
+}
+
+/// The error with its causes on one line.
+fn describe(e: &Error) -> String {
+    e.iter().map(ToString::to_string).collect::<Vec<_>>().join(": ")
+}
+
+/// The directory of a song in the download directory.
+fn directory_name(song: &SearchResult) -> String {
+    format!("{} - {}", song.artist, song.title)
+        .chars()
+        .map(|c| if c == '/' || c == '\\' || c.is_control() { '_' } else { c })
+        .collect::<String>()
+        .trim_start_matches('.')
+        .to_string()
+}
+
+/// A path from the server that stays inside the directory of the song.
+fn checked_path(path: &str) -> Result<PathBuf> {
+    let path = Path::new(path);
+    if path.as_os_str().is_empty() || !path.components().all(|component| matches!(component, Component::Normal(_))) {
+        return Err(format!("the path \"{}\" leaves the directory of the song", path.display()).into());
+    }
+    Ok(path.to_path_buf())
+}
+
+/// Download `url` to `path`, resuming a previous download.
+///
+/// Complete files are verified with the size and checksum the server sends.
+fn download_file(client: &Client, url: Url, path: &Path, progress: &mut dyn FnMut(&Progress)) -> Result<()> {
+    if path.exists() {
+        return Ok(());
+    }
+    let dir = path.parent().chain_err(|| "invalid download path")?;
+    fs::create_dir_all(dir).chain_err(|| format!("could not create directory {}", dir.display()))?;
+
+    let downloaded = fs::metadata(part_path(path)).map(|metadata| metadata.len()).unwrap_or(0);
+    let mut response = request(client, &url, downloaded)?;
+    if response.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+        // the part does not fit the file on the server anymore
+        response = request(client, &url, 0)?;
+    }
+    let response = response
+        .error_for_status()
+        .chain_err(|| format!("could not download {}", url))?;
+    let checksum = response
+        .headers()
+        .get(CHECKSUM_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.trim().to_lowercase());
+    let body = Body {
+        // servers that do not support ranges send the whole file
+        partial: response.status() == StatusCode::PARTIAL_CONTENT,
+        length: response.content_length(),
+        checksum,
+        reader: response,
+    };
+    save(body, path, progress)
+}
+
+/// The answer of a server to a download.
+struct Body<R> {
+    reader: R,
+    /// Whether the body continues the part downloaded before.
+    partial: bool,
+    /// The size of the body, if the server tells.
+    length: Option<u64>,
+    /// The SHA-256 hash of the whole file in lower case hex.
+    checksum: Option<String>,
+}
+
+/// Where a file is downloaded to until it is complete.
+fn part_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".part");
+    path.with_file_name(name)
+}
+
+/// Write `body` to the part of `path`, and move it to `path` once it is
+/// complete and verified.
+fn save<R: Read>(mut body: Body<R>, path: &Path, progress: &mut dyn FnMut(&Progress)) -> Result<()> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+    let part_path = part_path(path);
+
+    let mut downloaded = if body.partial {
+        fs::metadata(&part_path).map(|metadata| metadata.len()).unwrap_or(0)
+    } else {
+        0
+    };
+    let total = body.length.map(|length| length + downloaded);
+
+    let mut file = if downloaded > 0 {
+        OpenOptions::new().append(true).open(&part_path)
+    } else {
+        File::create(&part_path)
+    }
+    .chain_err(|| format!("could not write {}", part_path.display()))?;
+
+    let mut state = Progress {
+        name: &name,
+        downloaded,
+        total,
+        finished: false,
+    };
+    progress(&state);
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let read = body
+            .reader
+            .read(&mut buffer)
+            .chain_err(|| format!("download of {} interrupted, it is resumed next time", name))?;
+        if read == 0 {
+            break;
+        }
+        file.write_all(&buffer[..read])
+            .chain_err(|| format!("could not write {}", part_path.display()))?;
+        downloaded += read as u64;
+        state.downloaded = downloaded;
+        progress(&state);
+    }
+    state.finished = true;
+    progress(&state);
+    drop(file);
+
+    if let Some(total) = total {
+        if downloaded != total {
+            return Err(format!("download of {} is incomplete, got {} of {} bytes", name, downloaded, total).into());
+        }
+    }
+    if let Some(expected) = body.checksum {
+        if sha256(&part_path)? != expected {
+            // resuming a corrupt file would not help
+            let _ = fs::remove_file(&part_path);
+            return Err(format!("{} is corrupt, its checksum does not match", name).into());
+        }
+    }
+    fs::rename(&part_path, path).chain_err(|| format!("could not move {} into place", part_path.display()))
+}
+
+/// Request `url`, starting at byte `offset`.
+fn request(client: &Client, url: &Url, offset: u64) -> Result<Response> {
+    let mut request = client.get(url.clone());
+    if offset > 0 {
+        request = request.header(RANGE, format!("bytes={}-", offset));
+    }
+    request.send().chain_err(|| format!("could not download {}", url))
+}
+
+/// The SHA-256 hash of a file in hex.
+fn sha256(path: &Path) -> Result<String> {
+    let mut file = File::open(path).chain_err(|| format!("could not read {}", path.display()))?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher).chain_err(|| format!("could not read {}", path.display()))?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// How far the download of a file is.
+#[derive(Clone, Debug)]
+pub struct Progress<'a> {
+    /// The name of the file.
+    pub name: &'a str,
+    /// The bytes downloaded so far, including those of earlier attempts.
+    pub downloaded: u64,
+    /// The size of the file, if the server tells.
+    pub total: Option<u64>,
+    /// Whether all bytes were received.
+    pub finished: bool,
+}
+
+impl<'a> Progress<'a> {
+    /// A progress bar of the download, or the megabytes downloaded if the
+    /// size is unknown.
+    pub fn bar(&self) -> String {
+        match self.total {
+            Some(total) if total > 0 => {
+                let filled = ((self.downloaded * PROGRESS_WIDTH as u64 / total) as usize).min(PROGRESS_WIDTH);
+                format!(
+                    "[{}{}] {:3}% {}",
+                    "#".repeat(filled),
+                    " ".repeat(PROGRESS_WIDTH - filled),
+                    self.downloaded * 100 / total,
+                    self.name
+                )
+            }
+            _ => format!("{:.1} MB {}", (self.downloaded / 100_000) as f32 / 10.0, self.name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn song(artist: &str, title: &str) -> SearchResult {
+        SearchResult {
+            artist: artist.to_string(),
+            title: title.to_string(),
+            genre: None,
+            path: "song.txt".to_string(),
+            server: "http://localhost:8080".to_string(),
+        }
+    }
+
+    #[test]
+    fn keeps_paths_inside_the_song_directory() {
+        assert_eq!(checked_path("audio.mp3").unwrap(), PathBuf::from("audio.mp3"));
+        assert_eq!(checked_path("covers/front.jpg").unwrap(), PathBuf::from("covers/front.jpg"));
+        assert!(checked_path("").is_err());
+        assert!(checked_path("..").is_err());
+        assert!(checked_path("../audio.mp3").is_err());
+        assert!(checked_path("covers/../../audio.mp3").is_err());
+        assert!(checked_path("/etc/passwd").is_err());
+        assert!(checked_path("./audio.mp3").is_err());
+    }
+
+    #[test]
+    fn names_directories_without_separators_or_control_characters() {
+        assert_eq!(directory_name(&song("AC/DC", "T.N.T.")), "AC_DC - T.N.T.");
+        assert_eq!(directory_name(&song("a\\b", "line\nbreak\t")), "a_b - line_break_");
+        // no hidden directories, and no way up
+        assert_eq!(directory_name(&song("..", "/..")), " - _..");
+        assert_eq!(directory_name(&song(".hidden", "song")), "hidden - song");
+    }
+
+    /// The SHA-256 hash of "hello world".
+    const HELLO_WORLD_SHA256: &str = "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
+
+    fn body(content: &str, partial: bool) -> Body<&[u8]> {
+        Body {
+            reader: content.as_bytes(),
+            partial,
+            length: Some(content.len() as u64),
+            checksum: None,
+        }
+    }
+
+    #[test]
+    fn resumes_a_partial_download() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("song.mp3");
+        fs::write(part_path(&path), "hello ").unwrap();
+        let mut progress = Vec::new();
+        let body = Body {
+            checksum: Some(HELLO_WORLD_SHA256.to_string()),
+            ..body("world", true)
+        };
+        save(body, &path, &mut |state| progress.push((state.downloaded, state.total))).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "hello world");
+        assert!(!part_path(&path).exists());
+        assert_eq!(progress.first(), Some(&(6, Some(11))));
+        assert_eq!(progress.last(), Some(&(11, Some(11))));
+    }
+
+    #[test]
+    fn starts_over_if_the_server_sends_the_whole_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("song.mp3");
+        fs::write(part_path(&path), "stale").unwrap();
+        save(body("hello world", false), &path, &mut |_| {}).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "hello world");
+    }
+
+    #[test]
+    fn keeps_truncated_downloads_to_resume_them() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("song.mp3");
+        let body = Body {
+            length: Some(11),
+            ..body("hello", false)
+        };
+        assert!(save(body, &path, &mut |_| {}).is_err());
+        assert!(!path.exists());
+        assert_eq!(fs::read_to_string(part_path(&path)).unwrap(), "hello");
+    }
+
+    #[test]
+    fn deletes_downloads_with_the_wrong_checksum() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("song.mp3");
+        fs::write(part_path(&path), "hello ").unwrap();
+        let body = Body {
+            checksum: Some(HELLO_WORLD_SHA256.to_string()),
+            ..body("there", true)
+        };
+        assert!(save(body, &path, &mut |_| {}).is_err());
+        assert!(!path.exists());
+        assert!(!part_path(&path).exists());
+    }
+
+    #[test]
+    fn shows_the_progress_of_a_download() {
+        let mut progress = Progress {
+            name: "song.mp3",
+            downloaded: 1500,
+            total: Some(3000),
+            finished: false,
+        };
+        assert_eq!(progress.bar(), format!("[{}{}]  50% song.mp3", "#".repeat(15), " ".repeat(15)));
+        progress.downloaded = 3000;
+        assert_eq!(progress.bar(), format!("[{}] 100% song.mp3", "#".repeat(30)));
+        // servers may not tell the size
+        progress.total = None;
+        progress.downloaded = 2_500_000;
+        assert_eq!(progress.bar(), "2.5 MB song.mp3");
+    }
+}
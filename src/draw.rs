@@ -1,3 +1,5 @@
+//! Draws the lyrics, notes and sung pitches of the current lines into a frame.
+
 mod errors {
     use error_chain::error_chain;
 
@@ -5,85 +7,129 @@ mod errors {
 }
 use crate::errors::*;
 
-use colored::*;
+use colored::Color;
 use pitch_calc::*;
 
-pub fn generate_screen(
-    line: &ultrastar_txt::Line,
-    beat: f32,
-    dominant_note: Option<LetterOctave>,
-) -> Result<String> {
-    let (term_width, _term_height) =
-        termion::terminal_size().chain_err(|| "could not get terminal size")?;
-    let note_lines = draw_notelines(line, beat, term_width)?;
-    let lyric_line = gen_lyric_line(line, beat, term_width, dominant_note);
-
-    Ok(format!("{}{}", note_lines, lyric_line,))
-}
+use crate::config::Theme;
+use crate::frame::{Frame, Style};
+use crate::singer::Singer;
+use crate::song::{first_note_start, last_note_end};
+use crate::timeline::Timeline;
 
-fn draw_notelines(line: &ultrastar_txt::Line, beat: f32, term_width: u16) -> Result<String> {
-    // spacin between note lines
-    let line_spacing = 2;
-    // space to leave at the top (ex for progrss bar)
-    let top_offset = 2;
+/// Width of the meter showing how sharp or flat the singer is.
+const TUNING_METER_WIDTH: usize = 21;
+/// Number of rows the notes span at most, two per note position.
+const MAX_NOTE_ROWS: u16 = 16 * 2 + 1;
+/// Shown in the last row while the song is paused.
+const PAUSE_MESSAGE: &str = "Paused, press space to continue";
 
-    let mut output = String::new();
+/// A part of the song and the singers singing it.
+pub struct Part<'a> {
+    /// The line to show, if the part has any left.
+    pub line: Option<&'a ultrastar_txt::Line>,
+    /// The index of the note of `line` sung right now.
+    pub current_note: Option<usize>,
+    pub singers: Vec<&'a Singer>,
+}
 
-    let first_note_start = if let Some(note) = line.notes.first() {
-        match *note {
-            ultrastar_txt::Note::Regular {
-                start,
-                duration: _,
-                pitch: _,
-                text: _,
-            } => start,
-            ultrastar_txt::Note::Golden {
-                start,
-                duration: _,
-                pitch: _,
-                text: _,
-            } => start,
-            ultrastar_txt::Note::Freestyle {
-                start,
-                duration: _,
-                pitch: _,
-                text: _,
-            } => start,
-            ultrastar_txt::Note::PlayerChange { player: _ } => 0, // TODO: this is bad find better solution
+impl<'a> Part<'a> {
+    /// The line and note of `timeline` at `beat`, sung by `singers`.
+    pub fn at(timeline: &'a Timeline, beat: f32, singers: Vec<&'a Singer>) -> Part<'a> {
+        Part {
+            line: timeline.line_at(beat),
+            current_note: timeline.current_note_index(beat),
+            singers,
         }
-    } else {
-        return Err("line has no first note???".into());
-    };
+    }
+}
 
-    let last_note_end = if let Some(note) = line.notes.last() {
-        match *note {
-            ultrastar_txt::Note::Regular {
-                start,
-                duration,
-                pitch: _,
-                text: _,
-            } => start + duration,
-            ultrastar_txt::Note::Golden {
-                start,
-                duration,
-                pitch: _,
-                text: _,
-            } => start + duration,
-            ultrastar_txt::Note::Freestyle {
-                start,
-                duration,
-                pitch: _,
-                text: _,
-            } => start + duration,
-            ultrastar_txt::Note::PlayerChange { player: _ } => 0, // TODO: this is bad find better solution
+/// The rows of the screen a part is drawn in.
+struct Lane {
+    /// The first row of the lane, starting at 1.
+    top: u16,
+    height: u16,
+    /// Number of singers in this lane.
+    singers: u16,
+}
+
+impl Lane {
+    /// Number of rows available for notes.
+    fn note_rows(&self) -> u16 {
+        // progress bar, lyrics, pitch lines and score with a gap in between each
+        let reserved = 2 + 2 + self.singers + 1 + 1;
+        self.height.saturating_sub(reserved).clamp(2, MAX_NOTE_ROWS)
+    }
+
+    fn progress_row(&self) -> u16 {
+        self.top
+    }
+
+    fn note_row(&self, letter: Letter) -> u16 {
+        let pos = letter_to_pos(letter);
+        self.top + 2 + (16 - pos) * (self.note_rows() - 1) / 16
+    }
+
+    fn lyric_row(&self) -> u16 {
+        self.top + 2 + self.note_rows() + 1
+    }
+
+    fn pitch_row(&self, singer: u16) -> u16 {
+        self.lyric_row() + 2 + singer
+    }
+
+    fn score_row(&self) -> u16 {
+        self.pitch_row(self.singers)
+    }
+}
+
+/// Draw every part in its own lane of the frame, the first at the top.
+///
+/// The pitches of the singers are drawn at `sung_beat`, the beat they were
+/// sung at considering the latency of playback and capture.
+pub fn draw_screen(frame: &mut Frame, parts: &[Part], beat: f32, sung_beat: f32, theme: &Theme) -> Result<()> {
+    let lane_height = frame.height() / parts.len().max(1) as u16;
+
+    for (index, part) in parts.iter().enumerate() {
+        let lane = Lane {
+            top: 1 + index as u16 * lane_height,
+            height: lane_height,
+            singers: part.singers.len() as u16,
+        };
+        if let Some(line) = part.line {
+            draw_notelines(frame, line, part, beat, sung_beat, &lane, theme)?;
+            draw_lyric_line(frame, line, part.current_note, beat, &lane);
         }
-    } else {
-        return Err("line has no last note???".into());
-    };
+        for (row, singer) in part.singers.iter().enumerate() {
+            draw_pitch_line(frame, singer, lane.pitch_row(row as u16));
+        }
+        draw_score_line(frame, &part.singers, lane.score_row());
+    }
+
+    Ok(())
+}
+
+/// Show that the song is paused in the last row, over whatever is drawn there.
+pub fn draw_pause_banner(frame: &mut Frame) {
+    let banner = format!("{:width$}", PAUSE_MESSAGE, width = frame.width() as usize);
+    frame.put_str(1, frame.height(), &banner, Style::default().bold());
+}
+
+fn draw_notelines(
+    frame: &mut Frame,
+    line: &ultrastar_txt::Line,
+    part: &Part,
+    beat: f32,
+    sung_beat: f32,
+    lane: &Lane,
+    theme: &Theme,
+) -> Result<()> {
+    let term_width = frame.width();
+    let first_note_start = first_note_start(line).chain_err(|| "line has no first note???")?;
+    let last_note_end = last_note_end(line).chain_err(|| "line has no last note???")?;
 
     let chars_per_beat = term_width as f32 / (last_note_end - first_note_start) as f32;
 
-    for note in line.notes.iter() {
+    for (index, note) in line.notes.iter().enumerate() {
         let (start, duration, pitch, note_type) = match *note {
             ultrastar_txt::Note::Regular {
                 start,
@@ -107,34 +153,17 @@ fn draw_notelines(line: &ultrastar_txt::Line, beat: f32, term_width: u16) -> Res
         };
 
         // calculate position of current note
-        // terminal goto starts at 1
+        // terminal columns start at 1
         let note_hpos = ((start - first_note_start) as f32 * chars_per_beat) as u16 + 1;
-        let note_vpos =
-            (top_offset + 17 * line_spacing) - letter_to_pos(pitch.letter()) * line_spacing + 1;
+        let note_vpos = lane.note_row(pitch.letter());
 
-        let color_note = match note_type {
-            NoteType::Golden => {
-                Box::new(|note: &str| note.yellow().to_string()) as Box<dyn Fn(&str) -> String>
-            }
-            NoteType::Regular => {
-                Box::new(|note: &str| note.bright_blue().to_string()) as Box<dyn Fn(&str) -> String>
-            }
-            NoteType::Freestyle => {
-                Box::new(|note: &str| note.red().to_string()) as Box<dyn Fn(&str) -> String>
-            }
-        };
-
-        let color_played_note = match note_type {
-            NoteType::Golden => {
-                Box::new(|note: &str| note.bright_yellow().to_string()) as Box<dyn Fn(&str) -> String>
-            }
-            NoteType::Regular => {
-                Box::new(|note: &str| note.white().to_string()) as Box<dyn Fn(&str) -> String>
-            }
-            NoteType::Freestyle => {
-                Box::new(|note: &str| note.bright_red().to_string()) as Box<dyn Fn(&str) -> String>
-            }
+        let (note_color, played_note_color) = match note_type {
+            NoteType::Golden => (theme.golden_note, theme.golden_note_sung),
+            NoteType::Regular => (theme.regular_note, theme.regular_note_sung),
+            NoteType::Freestyle => (theme.freestyle_note, theme.freestyle_note_sung),
         };
+        let note_line = "#".repeat((duration as f32 * chars_per_beat) as usize);
+        let letter = format!("{:?}", pitch.letter());
 
         // note is current note or allready played
         if beat >= start as f32 {
@@ -142,68 +171,42 @@ fn draw_notelines(line: &ultrastar_txt::Line, beat: f32, term_width: u16) -> Res
             let times = (beat - start as f32) * chars_per_beat;
             if beat <= last_note_end as f32 {
                 let bar = "#".repeat(times.floor() as usize);
-                // terminal goto starts with 1
-                output.push_str(format!("{}{}", termion::cursor::Goto(1, 1), bar).as_ref());
+                frame.put_str(1, lane.progress_row(), &bar, Style::default());
             }
 
             // note is current note -> hightlight it
-            if (start + duration) as f32 >= beat {
+            if part.current_note == Some(index) {
                 let marked = (beat - start as f32) * chars_per_beat;
-                let note_line_str = color_note(
-                    "#".repeat((duration as f32 * chars_per_beat) as usize)
-                        .as_ref(),
-                );
-                let marked_line_str = color_played_note("#".repeat(marked as usize).as_ref());
-                output.push_str(
-                    format!(
-                        "{}{}{}{}{}{:?}",
-                        termion::cursor::Goto(note_hpos, note_vpos),
-                        note_line_str,
-                        termion::cursor::Goto(note_hpos, note_vpos),
-                        marked_line_str,
-                        termion::cursor::Goto(note_hpos, note_vpos),
-                        pitch.letter(),
-                    ).as_ref(),
-                );
+                frame.put_str(note_hpos, note_vpos, &note_line, Style::fg(note_color));
+                frame.put_str(note_hpos, note_vpos, &"#".repeat(marked as usize), Style::fg(played_note_color));
             }
             // note has been played
             else {
-                let played_line_str = color_played_note(
-                    "#".repeat((duration as f32 * chars_per_beat) as usize)
-                        .as_ref(),
-                );
-                output.push_str(
-                    format!(
-                        "{}{}{}{:?}",
-                        termion::cursor::Goto(note_hpos, note_vpos),
-                        played_line_str,
-                        termion::cursor::Goto(note_hpos, note_vpos),
-                        pitch.letter(),
-                    ).as_ref(),
-                );
+                frame.put_str(note_hpos, note_vpos, &note_line, Style::fg(played_note_color));
             }
         // note has not been played yet
         } else {
-            let note_line_str = color_note(
-                "#".repeat((duration as f32 * chars_per_beat) as usize)
-                    .as_ref(),
-            );
-            output.push_str(
-                format!(
-                    "{}{}{}{:?}",
-                    termion::cursor::Goto(note_hpos, note_vpos),
-                    note_line_str,
-                    termion::cursor::Goto(note_hpos, note_vpos),
-                    pitch.letter(),
-                ).as_ref(),
-            );
+            frame.put_str(note_hpos, note_vpos, &note_line, Style::fg(note_color));
         }
+        frame.put_str(note_hpos, note_vpos, &letter, Style::default());
     }
 
-    Ok(output)
+    // mark the pitch of every singer at the beat it was sung at
+    if sung_beat >= first_note_start as f32 && sung_beat <= last_note_end as f32 {
+        let marker_hpos = ((sung_beat - first_note_start as f32) * chars_per_beat) as u16 + 1;
+        for singer in part.singers.iter() {
+            if let Some(reading) = singer.reading() {
+                let marker_vpos = lane.note_row(reading.step.letter());
+                frame.put_str(marker_hpos.min(term_width), marker_vpos, "<", Style::fg(singer.color).bold());
+            }
+        }
+    }
+
+    Ok(())
 }
 
-fn line_to_str(line: &ultrastar_txt::Line) -> String {
+/// The text of all notes of a line.
+pub fn line_to_str(line: &ultrastar_txt::Line) -> String {
     let mut line_str = String::new();
     for note in line.notes.iter() {
         match *note {
@@ -238,21 +241,15 @@ enum NoteType {
     Freestyle,
 }
 
-fn gen_lyric_line(
-    line: &ultrastar_txt::Line,
-    beat: f32,
-    term_width: u16,
-    dominant_note: Option<LetterOctave>,
-) -> String {
+fn draw_lyric_line(frame: &mut Frame, line: &ultrastar_txt::Line, current_note: Option<usize>, beat: f32, lane: &Lane) {
     let uncolored_line = line_to_str(line);
 
-    // terminal goto starts at 1
-    let line_vpos = (term_width - uncolored_line.len() as u16) / 2 + 1;
-    let line_hpos = 2 + 17 * 2 + 10 + 1; // TODO this is below the lines but should not be a magic number
+    // terminal columns start at 1
+    let mut col = frame.width().saturating_sub(uncolored_line.chars().count() as u16) / 2 + 1;
+    let row = lane.lyric_row();
 
-    let mut lyric = format!("{}", termion::cursor::Goto(line_vpos, line_hpos));
-    for note in line.notes.iter() {
-        let (start, duration, _pitch, text, note_type) = match *note {
+    for (index, note) in line.notes.iter().enumerate() {
+        let (start, _duration, _pitch, text, note_type) = match *note {
             ultrastar_txt::Note::Regular {
                 start,
                 duration,
@@ -275,37 +272,87 @@ fn gen_lyric_line(
         };
 
         // note is current note or allready played
-        if beat >= start as f32 {
+        let style = if beat >= start as f32 {
             // note is current note -> hightlight it
-            if (start + duration) as f32 >= beat {
+            if current_note == Some(index) {
                 if note_type == NoteType::Golden {
-                    lyric.push_str(&text.black().on_bright_yellow().to_string());
+                    Style::fg(Color::Black).on(Color::BrightYellow)
                 } else {
-                    lyric.push_str(&text.black().on_bright_white().to_string());
+                    Style::fg(Color::Black).on(Color::BrightWhite)
                 }
             }
             // note has been played
             else if note_type == NoteType::Golden {
-                lyric.push_str(&text.yellow().to_string());
+                Style::fg(Color::Yellow)
             } else {
-                lyric.push_str(&text.white().to_string());
+                Style::fg(Color::White)
             }
         } else if note_type == NoteType::Golden {
-            lyric.push_str(&text.bright_yellow().to_string());
+            Style::fg(Color::BrightYellow)
         } else {
-            lyric.push_str(&text.bright_blue().to_string());
-        }
+            Style::fg(Color::BrightBlue)
+        };
+        col = frame.put_str(col, row, text, style);
     }
-    // add current note under the line
-    let note = match dominant_note {
-        Some(n) => format!("{:?}", n),
-        None => "                    ".to_string(),
+}
+
+/// Show the note sung by `singer` and how sharp or flat it is.
+fn draw_pitch_line(frame: &mut Frame, singer: &Singer, row: u16) {
+    // width of the name, the note, the frequency, the cents and the meter with spaces in between
+    let width = 3 + 1 + 4 + 1 + 7 + 1 + 6 + 1 + TUNING_METER_WIDTH + 2;
+    let col = frame.width().saturating_sub(width as u16) / 2 + 1;
+    let col = frame.put_str(col, row, &format!("{:<3}", singer.name), Style::fg(singer.color));
+    let reading = match singer.reading() {
+        Some(reading) => reading,
+        None => return,
     };
-    let line_hpos = 2 + 17 * 2 + 10 + 3; // TODO this is below the lines but should not be a magic number
-    let line_vpos = (term_width - note.len() as u16) / 2 + 1;
-    lyric.push_str(format!("{}{}", termion::cursor::Goto(line_vpos, line_hpos), note).as_ref());
 
-    lyric
+    let LetterOctave(letter, octave) = reading.note();
+    let note = format!("{:<4}", format!("{:?}{}", letter, octave));
+    let frequency = format!("{:4.0} Hz", reading.frequency);
+    let cents = format!("{:+3.0} ct", reading.cents);
+
+    // the meter spans from 50 cents flat to 50 cents sharp
+    let center = TUNING_METER_WIDTH / 2;
+    let offset = (reading.cents / 50.0 * center as f32).round() as i32;
+    let marker = (center as i32 + offset).clamp(0, TUNING_METER_WIDTH as i32 - 1) as usize;
+    let mut meter = String::new();
+    for i in 0..TUNING_METER_WIDTH {
+        meter.push(if i == marker {
+            '*'
+        } else if i == center {
+            '|'
+        } else {
+            '-'
+        });
+    }
+
+    let in_tune = reading.cents.abs() <= 15.0;
+    let col = frame.put_str(col + 1, row, &note, Style::default().bold());
+    let col = frame.put_str(col + 1, row, &frequency, Style::default());
+    let col = frame.put_str(
+        col + 1,
+        row,
+        &cents,
+        Style::fg(if in_tune { Color::Green } else { Color::Yellow }),
+    );
+    frame.put_str(col + 1, row, &format!("[{}]", meter), Style::default());
+}
+
+fn draw_score_line(frame: &mut Frame, singers: &[&Singer], row: u16) {
+    let scores: Vec<_> = singers
+        .iter()
+        .map(|singer| format!("{} {:5.0}", singer.name, singer.scorer.score().total()))
+        .collect();
+    let width = scores.iter().map(|score| score.len() + 3).sum::<usize>().saturating_sub(3);
+
+    let mut col = frame.width().saturating_sub(width as u16) / 2 + 1;
+    for (i, (score, singer)) in scores.iter().zip(singers.iter()).enumerate() {
+        if i > 0 {
+            col += 3;
+        }
+        col = frame.put_str(col, row, score, Style::fg(singer.color).bold());
+    }
 }
 
 fn letter_to_pos(letter: Letter) -> u16 {
@@ -329,3 +376,110 @@ fn letter_to_pos(letter: Letter) -> u16 {
         Letter::B => 16,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{
+        env, fs,
+        path::Path,
+        sync::{Arc, Mutex},
+        time::Instant,
+    };
+
+    use crate::capture::InputState;
+    use crate::config::ThemeConfig;
+    use crate::pitch::PitchReading;
+    use crate::score::Difficulty;
+    use crate::song;
+
+    const SAMPLE_SONG: &str = "songs/Pornophonique - Space - Invaders/Pornophonique - Space Invaders.txt";
+
+    /// Compare `actual` with the snapshot called `name`.
+    ///
+    /// `UPDATE_SNAPSHOTS=1` records the snapshots instead, missing ones fail.
+    fn assert_snapshot(name: &str, actual: &str) {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("src/draw/snapshots")
+            .join(format!("{}.txt", name));
+        if env::var_os("UPDATE_SNAPSHOTS").is_some() {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, actual).unwrap();
+            return;
+        }
+        match fs::read_to_string(&path) {
+            Ok(expected) => assert!(
+                actual == expected,
+                "{} differs, run with UPDATE_SNAPSHOTS=1 to record it again:\n{}",
+                path.display(),
+                actual
+            ),
+            Err(e) => panic!(
+                "could not read {}: {}, run with UPDATE_SNAPSHOTS=1 to record it:\n{}",
+                path.display(),
+                e,
+                actual
+            ),
+        }
+    }
+
+    /// Draw the sample song at `beat` with a singer singing an A4 slightly sharp.
+    fn render_sample_song(beat: f32) -> Frame {
+        let song = song::load(&Path::new(env!("CARGO_MANIFEST_DIR")).join(SAMPLE_SONG)).unwrap();
+        let timeline = Timeline::new(song.lines.clone());
+        let theme = Theme::from_config(&ThemeConfig::default()).unwrap();
+
+        let reading = PitchReading {
+            frequency: 441.0,
+            step: Step(57.0),
+            cents: 4.0,
+            clarity: 0.9,
+            rms: 0.1,
+            timestamp: Instant::now(),
+        };
+        let state = InputState {
+            reading: Some(reading),
+            error: None,
+        };
+        let mut singer = Singer::new(0, Arc::new(Mutex::new(state)), 0, &song.lines, Difficulty::Medium, theme.singer_color(0));
+        singer.update(beat).unwrap();
+
+        let parts = [Part::at(&timeline, beat, vec![&singer])];
+        let mut frame = Frame::new(80, 24);
+        draw_screen(&mut frame, &parts, beat, beat, &theme).unwrap();
+        frame
+    }
+
+    #[test]
+    fn draws_the_first_line_of_the_sample_song() {
+        assert_snapshot("sample_song_beat_20", &render_sample_song(20.0).to_plain_text());
+    }
+
+    #[test]
+    fn draws_a_later_line_of_the_sample_song() {
+        assert_snapshot("sample_song_beat_100", &render_sample_song(100.0).to_plain_text());
+    }
+
+    #[test]
+    fn draws_the_pause_banner_over_the_last_row() {
+        let mut frame = render_sample_song(20.0);
+        draw_pause_banner(&mut frame);
+        assert_snapshot("sample_song_paused", &frame.to_plain_text());
+    }
+
+    #[test]
+    fn draws_nothing_but_the_singer_before_the_song() {
+        let theme = Theme::from_config(&ThemeConfig::default()).unwrap();
+        let state = Arc::new(Mutex::new(InputState::default()));
+        let singer = Singer::new(0, state, 0, &[], Difficulty::Medium, theme.singer_color(0));
+        let parts = [Part {
+            line: None,
+            current_note: None,
+            singers: vec![&singer],
+        }];
+        let mut frame = Frame::new(40, 10);
+        draw_screen(&mut frame, &parts, 0.0, 0.0, &theme).unwrap();
+        assert_snapshot("no_line", &frame.to_plain_text());
+    }
+}
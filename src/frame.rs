@@ -0,0 +1,238 @@
+//! A grid of styled cells the screen is drawn into.
+//!
+//! Drawing into a `Frame` needs no terminal, so screens can be rendered
+//! anywhere and compared as plain text.  A `Flusher` brings the terminal up
+//! to date with a frame, writing only the cells that changed since the last
+//! frame it showed.
+
+use colored::{Color, Colorize};
+
+/// How the text of a cell looks.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Style {
+    /// The text color, `None` for the default of the terminal.
+    pub fg: Option<Color>,
+    /// The background color, `None` for the default of the terminal.
+    pub bg: Option<Color>,
+    pub bold: bool,
+}
+
+impl Style {
+    /// Text in the given color.
+    pub fn fg(color: Color) -> Style {
+        Style {
+            fg: Some(color),
+            ..Style::default()
+        }
+    }
+
+    /// The same style on the given background.
+    pub fn on(self, color: Color) -> Style {
+        Style { bg: Some(color), ..self }
+    }
+
+    /// The same style in bold.
+    pub fn bold(self) -> Style {
+        Style { bold: true, ..self }
+    }
+
+    /// Apply the style to `text`, with the escapes of `colored`.
+    fn paint(&self, text: &str) -> String {
+        let mut painted = text.normal();
+        if let Some(fg) = self.fg {
+            painted = painted.color(fg);
+        }
+        if let Some(bg) = self.bg {
+            painted = painted.on_color(bg);
+        }
+        if self.bold {
+            painted = painted.bold();
+        }
+        painted.to_string()
+    }
+}
+
+/// A character on the screen and its style.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Cell {
+    pub ch: char,
+    pub style: Style,
+}
+
+impl Default for Cell {
+    fn default() -> Cell {
+        Cell {
+            ch: ' ',
+            style: Style::default(),
+        }
+    }
+}
+
+/// The cells of a screen.
+///
+/// Columns and rows start at 1, like the cursor positions of the terminal.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Frame {
+    width: u16,
+    height: u16,
+    cells: Vec<Cell>,
+}
+
+impl Frame {
+    /// An empty frame of the given size.
+    pub fn new(width: u16, height: u16) -> Frame {
+        Frame {
+            width,
+            height,
+            cells: vec![Cell::default(); width as usize * height as usize],
+        }
+    }
+
+    pub fn width(&self) -> u16 {
+        self.width
+    }
+
+    pub fn height(&self) -> u16 {
+        self.height
+    }
+
+    /// Empty all cells.
+    pub fn clear(&mut self) {
+        self.cells.iter_mut().for_each(|cell| *cell = Cell::default());
+    }
+
+    /// The cell at `col` and `row`, `None` outside of the frame.
+    pub fn cell(&self, col: u16, row: u16) -> Option<&Cell> {
+        self.index(col, row).map(|index| &self.cells[index])
+    }
+
+    /// Write `text` from `col` on, cutting off what does not fit.
+    ///
+    /// Returns the column after the text.
+    pub fn put_str(&mut self, col: u16, row: u16, text: &str, style: Style) -> u16 {
+        let mut col = col;
+        for ch in text.chars() {
+            if let Some(index) = self.index(col, row) {
+                self.cells[index] = Cell { ch, style };
+            }
+            col = col.saturating_add(1);
+        }
+        col
+    }
+
+    /// The characters of every row, without trailing spaces.
+    pub fn to_plain_text(&self) -> String {
+        let mut text = String::new();
+        for row in self.cells.chunks(self.width.max(1) as usize) {
+            let line: String = row.iter().map(|cell| cell.ch).collect();
+            text.push_str(line.trim_end());
+            text.push('\n');
+        }
+        text
+    }
+
+    fn index(&self, col: u16, row: u16) -> Option<usize> {
+        if col == 0 || row == 0 || col > self.width || row > self.height {
+            return None;
+        }
+        Some((row - 1) as usize * self.width as usize + (col - 1) as usize)
+    }
+}
+
+/// Writes frames to the terminal, only the cells that changed.
+#[derive(Debug, Default)]
+pub struct Flusher {
+    /// The frame on the screen, `None` if the screen is unknown.
+    shown: Option<Frame>,
+}
+
+impl Flusher {
+    pub fn new() -> Flusher {
+        Flusher::default()
+    }
+
+    /// Forget what is on the screen, e.g. after writing to it directly.
+    ///
+    /// The next frame clears the screen and is drawn in full.
+    pub fn reset(&mut self) {
+        self.shown = None;
+    }
+
+    /// The escapes turning the screen into `frame`.
+    pub fn flush(&mut self, frame: &Frame) -> String {
+        let mut output = String::new();
+        let shown = match self.shown.take() {
+            Some(shown) if shown.width == frame.width && shown.height == frame.height => shown,
+            _ => {
+                output.push_str(termion::clear::All.as_ref());
+                Frame::new(frame.width, frame.height)
+            }
+        };
+
+        for row in 1..=frame.height {
+            let mut col = 1;
+            while col <= frame.width {
+                let index = frame.index(col, row).unwrap();
+                if frame.cells[index] == shown.cells[index] {
+                    col += 1;
+                    continue;
+                }
+                // write the changed cells of the same style at once
+                let style = frame.cells[index].style;
+                let start = col;
+                let mut text = String::new();
+                while col <= frame.width {
+                    let index = frame.index(col, row).unwrap();
+                    let cell = frame.cells[index];
+                    if cell == shown.cells[index] || cell.style != style {
+                        break;
+                    }
+                    text.push(cell.ch);
+                    col += 1;
+                }
+                output.push_str(&format!("{}{}", termion::cursor::Goto(start, row), style.paint(&text)));
+            }
+        }
+
+        self.shown = Some(frame.clone());
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cuts_off_text_outside_of_the_frame() {
+        let mut frame = Frame::new(5, 2);
+        assert_eq!(frame.put_str(3, 1, "abcd", Style::default()), 7);
+        frame.put_str(0, 2, "xy", Style::default());
+        frame.put_str(1, 3, "z", Style::default());
+        assert_eq!(frame.to_plain_text(), "  abc\ny\n");
+    }
+
+    #[test]
+    fn flushes_only_changed_cells() {
+        let clear = termion::clear::All.to_string();
+        let mut flusher = Flusher::new();
+        let mut frame = Frame::new(10, 3);
+        frame.put_str(1, 1, "hello", Style::default());
+        let first = flusher.flush(&frame);
+        assert!(first.starts_with(&clear));
+        assert!(first.contains("hello"));
+
+        assert_eq!(flusher.flush(&frame), "");
+
+        frame.put_str(2, 1, "a", Style::default());
+        frame.put_str(4, 3, "yo", Style::fg(Color::Red));
+        let changes = flusher.flush(&frame);
+        assert!(changes.contains(&format!("{}", termion::cursor::Goto(2, 1))));
+        assert!(changes.contains(&format!("{}", termion::cursor::Goto(4, 3))));
+        assert!(!changes.contains("hello"));
+        assert!(!changes.contains(&clear));
+
+        flusher.reset();
+        assert!(flusher.flush(&frame).starts_with(&clear));
+    }
+}
@@ -0,0 +1,564 @@
+//! Plays a song while the singers sing along.
+//!
+//! A `Session` is a song being sung, without any terminal: the song is played
+//! by a `Player` and the microphones are captured in the background.  Every
+//! tick, the readings of the singers are scored at the beat they were sung to
+//! and the lyrics are drawn with the notes and pitches into a `Frame`.
+//!
+//! `play` runs a session in the terminal, where the keys pause, seek and
+//! restart the song and the scores are shown at the end.  Other front-ends
+//! drive a session the same way.
+
+use crate::errors::*;
+
+use std::{
+    io::{stdout, Write},
+    path::Path,
+    time::Duration,
+};
+
+use log::warn;
+use termion::{event::Key, input::TermRead, raw::IntoRawMode, screen::AlternateScreen};
+use ultrastar_txt::{Header, Line};
+
+use crate::capture::{self, CaptureSettings, Microphone, SharedReading};
+use crate::config::Theme;
+use crate::content_providers::{get_url_content_provider, resolve_local_path};
+use crate::draw;
+use crate::frame::{Flusher, Frame};
+use crate::playback::{GStreamerPlayer, NullPlayer, Player, PlayerEvent};
+use crate::results::Results;
+use crate::score::{Difficulty, Score, Scorer};
+use crate::singer::Singer;
+use crate::song::{self, Song};
+use crate::timeline::Timeline;
+use crate::timing::Timing;
+
+/// How far the arrow keys seek, in ms.
+const SEEK_STEP_MS: f32 = 5000.0;
+/// How long to wait for player events before drawing the next frame.
+const FRAME_TIME: Duration = Duration::from_millis(10);
+/// How long the null player plays after the last note, in ms.
+const NULL_PLAYER_TAIL_MS: f32 = 2000.0;
+
+/// How a song is played and sung.
+#[derive(Clone, Debug)]
+pub struct Settings {
+    /// The microphone of every singer.
+    pub microphones: Vec<Microphone>,
+    pub capture: CaptureSettings,
+    pub difficulty: Difficulty,
+    pub theme: Theme,
+    /// The delay between playing and capturing, see `calibration`.
+    pub latency_ms: f32,
+    /// Play nothing and only keep the time, to sing without a sound card.
+    pub null_player: bool,
+}
+
+/// The beats of the song at a position of the playback.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Moment {
+    /// The beat being played.
+    pub beat: f32,
+    /// The beat the singers sing to, considering the latency.
+    pub sung_beat: f32,
+    /// Whether other lines are shown than at the moment before.
+    pub lines_changed: bool,
+}
+
+/// What a tick of a session did.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Tick {
+    /// Nothing to draw, e.g. because the song is paused.
+    Idle,
+    /// The singers were scored and the song drawn at the moment.
+    Drawn(Moment),
+    /// The song is over, `finished` if it was played to its end.
+    Over { finished: bool },
+}
+
+/// A song being sung.
+pub struct Session {
+    header: Header,
+    timing: Timing,
+    /// The lines of every part.
+    tracks: Vec<Vec<Line>>,
+    timelines: Vec<Timeline>,
+    /// The lines of all parts, to jump between them.
+    song_timeline: Timeline,
+    singers: Vec<Singer>,
+    difficulty: Difficulty,
+    theme: Theme,
+    latency_ms: f32,
+    player: Box<dyn Player>,
+    /// How far the played media is ahead of the song, in ms.
+    media_offset: f32,
+    /// The part of the song to play, #START is in seconds and #END in ms.
+    start_ms: f32,
+    end_ms: Option<f32>,
+    playing: bool,
+    /// The lines shown, `None` after seeking.
+    shown_lines: Option<Vec<Option<usize>>>,
+}
+
+impl Session {
+    /// Load the song at `song_path`, its audio and start capturing the microphones.
+    ///
+    /// GStreamer has to be initialized already, unless the null player is used.
+    pub fn load(song_path: &Path, settings: &Settings) -> Result<Session> {
+        // parse txt file
+        let song = song::load(song_path).chain_err(|| "could not parse song file")?;
+
+        // the files of the song are relative to the song file
+        let song_dir = song_path.parent().unwrap_or_else(|| Path::new(""));
+        for image_path in [&song.header.cover_path, &song.header.background_image_path].iter().copied().flatten() {
+            // images are not shown yet, so a missing one is no reason to stop
+            if let Err(e) = resolve_local_path(image_path, song_dir) {
+                warn!("{}", e);
+            }
+        }
+
+        let (player, media_offset): (Box<dyn Player>, f32) = if settings.null_player {
+            // nothing is heard, so the song lasts until its last note
+            let last_beat = song.lines.iter().filter_map(song::last_note_end).max().unwrap_or(0);
+            let mut player = NullPlayer::new(Some(song.timing.ms_at(last_beat as f32) + NULL_PLAYER_TAIL_MS), true);
+            player.load(&song.header.audio_path)?;
+            (Box::new(player), 0.0)
+        } else {
+            let (url, media_offset) = media_url(&song.header, song_dir)?;
+            let mut player = GStreamerPlayer::new();
+            player.load(&url)?;
+            (Box::new(player), media_offset)
+        };
+
+        // start capturing the microphones
+        let readings = capture::start(&settings.microphones, &settings.capture)?;
+        Ok(Session::new(song, player, media_offset, readings, settings))
+    }
+
+    /// Sing `song` with a singer for every reading.
+    ///
+    /// The song has to be loaded into `player` already, its media being
+    /// `media_offset` ms ahead of the song.
+    pub fn new(
+        song: Song,
+        player: Box<dyn Player>,
+        media_offset: f32,
+        readings: Vec<SharedReading>,
+        settings: &Settings,
+    ) -> Session {
+        // duets have one track per part, other songs just one
+        let tracks = song::split_tracks(&song.lines);
+        let timelines: Vec<Timeline> = tracks.iter().cloned().map(Timeline::new).collect();
+        let mut all_lines: Vec<Line> = tracks.concat();
+        all_lines.sort_by_key(|line| line.start);
+        let song_timeline = Timeline::new(all_lines);
+
+        let singers = readings
+            .into_iter()
+            .enumerate()
+            .map(|(index, reading)| {
+                let track = index % tracks.len();
+                let color = settings.theme.singer_color(index);
+                Singer::new(index, reading, track, &tracks[track], settings.difficulty, color)
+            })
+            .collect();
+
+        let start_ms = song::unknown_tag_f32(&song.header, "START").map(|start| start * 1000.0).unwrap_or(0.0).max(0.0);
+        let end_ms = song::unknown_tag_f32(&song.header, "END");
+
+        Session {
+            header: song.header,
+            timing: song.timing,
+            tracks,
+            timelines,
+            song_timeline,
+            singers,
+            difficulty: settings.difficulty,
+            theme: settings.theme.clone(),
+            latency_ms: settings.latency_ms,
+            player,
+            media_offset,
+            start_ms,
+            end_ms,
+            playing: false,
+            shown_lines: None,
+        }
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// Start playing the song at its start.
+    pub fn start(&mut self) -> Result<()> {
+        if self.start_ms > 0.0 {
+            self.player
+                .seek(self.start_ms + self.media_offset)
+                .chain_err(|| "could not seek to the start of the song")?;
+        }
+        self.player.play()
+    }
+
+    /// The position of the playback in the song, in ms.
+    pub fn position_ms(&self) -> f32 {
+        self.player.position().unwrap_or(0.0) - self.media_offset
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playing
+    }
+
+    /// Wait for the player, then score the singers and draw the song at its position.
+    ///
+    /// The frame is cleared when other lines are shown.  Once the song is
+    /// paused, the pause banner is drawn over the last moment.
+    pub fn tick(&mut self, frame: &mut Frame) -> Result<Tick> {
+        match self.player.next_event(FRAME_TIME) {
+            // the player logged the error already
+            Some(PlayerEvent::Error(_)) => {
+                self.playing = false;
+                return Ok(Tick::Over { finished: false });
+            }
+            Some(PlayerEvent::EndOfStream) => {
+                self.playing = false;
+                return Ok(Tick::Over { finished: true });
+            }
+            Some(PlayerEvent::StateChanged { playing: true }) => {
+                self.playing = true;
+                // the next moment is drawn from scratch, without the pause banner
+                self.shown_lines = None;
+                return Ok(Tick::Idle);
+            }
+            Some(PlayerEvent::StateChanged { playing: false }) => {
+                self.playing = false;
+                let position_ms = self.position_ms();
+                let moment = Moment {
+                    beat: self.timing.beat_at(position_ms),
+                    sung_beat: self.timing.beat_at(position_ms - self.latency_ms),
+                    lines_changed: false,
+                };
+                draw::draw_pause_banner(frame);
+                return Ok(Tick::Drawn(moment));
+            }
+            None if !self.playing => return Ok(Tick::Idle),
+            None => {}
+        }
+
+        let position_ms = self.position_ms();
+        // #END stops the song early
+        if self.end_ms.map(|end_ms| position_ms >= end_ms).unwrap_or(false) {
+            return Ok(Tick::Over { finished: true });
+        }
+        let moment = self.update(position_ms)?;
+        if moment.lines_changed {
+            frame.clear();
+        }
+        self.draw(frame, &moment)?;
+        Ok(Tick::Drawn(moment))
+    }
+
+    /// Score the singers at `position_ms` of the song.
+    ///
+    /// Fails if a microphone stopped capturing.
+    pub fn update(&mut self, position_ms: f32) -> Result<Moment> {
+        let beat = self.timing.beat_at(position_ms);
+        // what is captured now was sung to what was played before
+        let sung_beat = self.timing.beat_at(position_ms - self.latency_ms);
+        for singer in self.singers.iter_mut() {
+            singer.update(sung_beat)?;
+        }
+
+        // the lines follow the beat, wherever it jumps
+        let lines: Vec<Option<usize>> = self.timelines.iter().map(|timeline| timeline.line_index_at(beat)).collect();
+        let lines_changed = self.shown_lines.as_ref() != Some(&lines);
+        self.shown_lines = Some(lines);
+        Ok(Moment { beat, sung_beat, lines_changed })
+    }
+
+    /// The lines of every part at `beat` with the singers singing them.
+    pub fn parts(&self, beat: f32) -> Vec<draw::Part<'_>> {
+        self.timelines
+            .iter()
+            .enumerate()
+            .map(|(track, timeline)| {
+                draw::Part::at(timeline, beat, self.singers.iter().filter(|singer| singer.track == track).collect())
+            })
+            .collect()
+    }
+
+    /// Draw the lyrics, notes and pitches at `moment` into the frame.
+    pub fn draw(&self, frame: &mut Frame, moment: &Moment) -> Result<()> {
+        draw::draw_screen(frame, &self.parts(moment.beat), moment.beat, moment.sung_beat, &self.theme)
+    }
+
+    /// The name and score of every singer so far.
+    pub fn scores(&self) -> Vec<(&str, Score)> {
+        self.singers
+            .iter()
+            .map(|singer| (singer.name.as_str(), singer.scorer.score()))
+            .collect()
+    }
+
+    /// Pause the song or continue it.
+    ///
+    /// Returns whether the song is paused.
+    pub fn toggle_pause(&mut self) -> Result<bool> {
+        if self.playing {
+            self.player.pause()?;
+        } else {
+            self.player.play()?;
+        }
+        Ok(self.playing)
+    }
+
+    /// Seek `step_ms` forward, or backward if it is negative.
+    pub fn seek_by(&mut self, step_ms: f32) -> Result<()> {
+        self.seek(self.position_ms() + step_ms)
+    }
+
+    /// Seek to the start of the line before, or to the start of the song.
+    pub fn previous_line(&mut self) -> Result<()> {
+        let beat = self.timing.beat_at(self.position_ms());
+        let target = self
+            .song_timeline
+            .previous_line_start(beat)
+            .map(|start| self.timing.ms_at(start as f32))
+            .unwrap_or(self.start_ms);
+        self.seek(target)
+    }
+
+    /// Seek to the start of the next line, if there is one.
+    pub fn next_line(&mut self) -> Result<()> {
+        let beat = self.timing.beat_at(self.position_ms());
+        match self.song_timeline.next_line_start(beat) {
+            Some(start) => self.seek(self.timing.ms_at(start as f32)),
+            None => Ok(()),
+        }
+    }
+
+    /// Sing the song again from its start, forgetting the scores.
+    pub fn restart(&mut self) -> Result<()> {
+        for singer in self.singers.iter_mut() {
+            singer.scorer = Scorer::new(&self.tracks[singer.track], self.difficulty);
+        }
+        self.seek(self.start_ms)
+    }
+
+    /// Seek to `target_ms` of the song, within the part to play.
+    ///
+    /// The beats skipped are not scored.
+    pub fn seek(&mut self, target_ms: f32) -> Result<()> {
+        let mut target_ms = target_ms.max(self.start_ms);
+        if let Some(duration) = self.player.duration() {
+            target_ms = target_ms.min(duration - self.media_offset);
+        }
+        self.player.seek(target_ms + self.media_offset)?;
+
+        let sung_beat = self.timing.beat_at(target_ms - self.latency_ms);
+        for singer in self.singers.iter_mut() {
+            singer.scorer.seek(sung_beat);
+        }
+        self.shown_lines = None;
+        Ok(())
+    }
+
+    /// Stop playing and score what is left of the song.
+    ///
+    /// Returns the name and results of every singer.
+    pub fn finish(self) -> Vec<(String, Results)> {
+        let Session { player, mut singers, tracks, .. } = self;
+        // stop playing
+        drop(player);
+        singers
+            .iter_mut()
+            .map(|singer| {
+                singer.scorer.finish();
+                (singer.name.clone(), Results::new(&singer.scorer, &tracks[singer.track]))
+            })
+            .collect()
+    }
+}
+
+/// The url of the audio of a song and how far it is ahead of the song, in ms.
+///
+/// The video is the fallback if the audio is missing.
+fn media_url(header: &Header, song_dir: &Path) -> Result<(String, f32)> {
+    let (content_provider, media_offset) = match get_url_content_provider(&header.audio_path, song_dir) {
+        Ok(content_provider) => (content_provider, 0.0),
+        Err(e) => match header.video_path.as_ref() {
+            Some(video_path) => {
+                warn!("{}, playing the video {} instead", e, video_path);
+                // the video is #VIDEOGAP seconds ahead of the audio
+                let content_provider = get_url_content_provider(video_path, song_dir)?;
+                (content_provider, header.video_gap.unwrap_or(0.0) * 1000.0)
+            }
+            None => return Err(e),
+        },
+    };
+
+    let urls = content_provider.urls();
+    let url = urls.first().chain_err(|| "no audio found for the song")?;
+    Ok((url.to_string(), media_offset))
+}
+
+/// Play a session in the terminal until the song ends or the singers quit.
+///
+/// Takes over the terminal while playing and shows the results of every
+/// singer if the song was played to its end.  Returns the results.
+pub fn play(mut session: Session) -> Result<Vec<(String, Results)>> {
+    session.start()?;
+
+    // get access to terminal, raw mode to react to single key presses
+    let mut stdout = AlternateScreen::from(
+        stdout()
+            .into_raw_mode()
+            .chain_err(|| "could not switch terminal to raw mode")?,
+    );
+    // don't block the main loop while waiting for keys
+    let mut keys = termion::async_stdin().keys();
+
+    // the screen is drawn into a frame, only its changes are written
+    let mut frame = Frame::new(0, 0);
+    let mut flusher = Flusher::new();
+
+    // begin main loop
+    let finished = loop {
+        if let Some(key) = keys.next() {
+            match key.chain_err(|| "could not read from stdin")? {
+                Key::Char(' ') => {
+                    session.toggle_pause()?;
+                }
+                Key::Left => session.seek_by(-SEEK_STEP_MS)?,
+                Key::Right => session.seek_by(SEEK_STEP_MS)?,
+                Key::Up => session.previous_line()?,
+                Key::Down => session.next_line()?,
+                Key::Char('r') => session.restart()?,
+                Key::Char('q') | Key::Esc | Key::Ctrl('c') => break false,
+                _ => {}
+            }
+        }
+
+        let (term_width, term_height) = termion::terminal_size().chain_err(|| "could not get terminal size")?;
+        if (frame.width(), frame.height()) != (term_width, term_height) {
+            frame = Frame::new(term_width, term_height);
+        }
+        match session.tick(&mut frame)? {
+            Tick::Idle => {}
+            Tick::Drawn(_) => {
+                write!(stdout, "{}", flusher.flush(&frame)).chain_err(|| "could not write to stdout")?;
+                stdout.flush().chain_err(|| "could not flush stdout")?;
+            }
+            Tick::Over { finished } => break finished,
+        }
+    };
+    // end main loop
+
+    let results = session.finish();
+    if finished {
+        // wait for any key without the need to press enter
+        for (name, singer_results) in results.iter() {
+            let (term_width, term_height) = termion::terminal_size().chain_err(|| "could not get terminal size")?;
+            frame = Frame::new(term_width, term_height);
+            singer_results.draw_screen(&mut frame, name);
+            write!(stdout, "{}", flusher.flush(&frame)).chain_err(|| "could not write to stdout")?;
+            stdout.flush().chain_err(|| "could not flush stdout")?;
+            while keys.next().is_none() {
+                std::thread::sleep(Duration::from_millis(20));
+            }
+        }
+    }
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::{Arc, Mutex};
+
+    use crate::capture::InputState;
+    use crate::config::ThemeConfig;
+
+    const SAMPLE_SONG: &str = "songs/Pornophonique - Space - Invaders/Pornophonique - Space Invaders.txt";
+
+    /// A session of the sample song with one singer, the virtual clock
+    /// advancing by the frame time of every tick.
+    fn sample_session(duration_ms: f32) -> Session {
+        let song = song::load(&Path::new(env!("CARGO_MANIFEST_DIR")).join(SAMPLE_SONG)).unwrap();
+        let mut player = NullPlayer::new(Some(duration_ms), false);
+        player.load(&song.header.audio_path).unwrap();
+        let settings = Settings {
+            microphones: Vec::new(),
+            capture: CaptureSettings::default(),
+            difficulty: Difficulty::Medium,
+            theme: Theme::from_config(&ThemeConfig::default()).unwrap(),
+            latency_ms: 0.0,
+            null_player: true,
+        };
+        let readings = vec![Arc::new(Mutex::new(InputState::default()))];
+        Session::new(song, Box::new(player), 0.0, readings, &settings)
+    }
+
+    #[test]
+    fn plays_the_song_while_drawing() {
+        let mut session = sample_session(300.0);
+        session.start().unwrap();
+        let mut frame = Frame::new(80, 24);
+        let mut drawn = Vec::new();
+        let finished = loop {
+            match session.tick(&mut frame).unwrap() {
+                Tick::Idle => {}
+                Tick::Drawn(moment) => drawn.push((session.position_ms(), moment.beat)),
+                Tick::Over { finished } => break finished,
+            }
+        };
+
+        assert!(finished);
+        assert!(!session.is_playing());
+        // every tick draws the song one frame further
+        let positions: Vec<f32> = drawn.iter().map(|(position, _)| *position).collect();
+        let expected: Vec<f32> = (1..30).map(|frame| frame as f32 * 10.0).collect();
+        assert_eq!(positions, expected);
+        // #BPM:315,08 in quarter beats, #GAP:2720
+        for (position, beat) in drawn {
+            let expected = (position - 2720.0) * 315.08 * 4.0 / 60_000.0;
+            assert!((beat - expected).abs() < 0.01, "beat {} at {} ms", beat, position);
+        }
+        assert_eq!(session.scores().len(), 1);
+
+        let results = session.finish();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].0, "P1");
+    }
+
+    #[test]
+    fn shows_the_pause_banner_until_the_song_continues() {
+        let mut session = sample_session(10_000.0);
+        session.start().unwrap();
+        let mut frame = Frame::new(80, 24);
+        let last_row = |frame: &Frame| frame.to_plain_text().lines().last().unwrap().to_string();
+        while session.position_ms() < 50.0 {
+            session.tick(&mut frame).unwrap();
+        }
+
+        assert!(session.toggle_pause().unwrap());
+        match session.tick(&mut frame).unwrap() {
+            Tick::Drawn(moment) => assert!(!moment.lines_changed),
+            tick => panic!("paused with {:?}", tick),
+        }
+        assert_eq!(last_row(&frame), "Paused, press space to continue");
+        // the song stands still while paused
+        assert_eq!(session.tick(&mut frame).unwrap(), Tick::Idle);
+        assert_eq!(session.position_ms(), 50.0);
+
+        assert!(!session.toggle_pause().unwrap());
+        assert_eq!(session.tick(&mut frame).unwrap(), Tick::Idle);
+        match session.tick(&mut frame).unwrap() {
+            Tick::Drawn(moment) => assert!(moment.lines_changed),
+            tick => panic!("continued with {:?}", tick),
+        }
+        assert_eq!(session.position_ms(), 60.0);
+        assert!(!last_row(&frame).contains("Paused"));
+    }
+}
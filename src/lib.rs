@@ -0,0 +1,52 @@
+//! A karaoke game for the terminal, playing UltraStar songs.
+//!
+//! The engine is split into modules that can be used on their own:
+//!
+//! * `song`, `timing` and `timeline` load songs and find the lines and notes
+//!   at any time of the song.
+//! * `audio_input` and `capture` capture the singers, `pitch` detects what
+//!   they sing and `score` rates it against the notes.
+//! * `draw` and `results` render the lyrics and scores for the terminal,
+//!   `draw` into a `frame` that is written to the terminal cell by cell.
+//! * `playback` plays the songs, `content_providers` finds their audio.
+//! * `library`, `server_interface` and `download` find songs locally and on
+//!   song servers.
+//! * `game` puts it all together in a `Session` that plays and scores a song,
+//!   and `game::play` runs it in the terminal.
+//!
+//! Most functions return the `errors::Result` of this crate.  Playing or
+//! capturing with GStreamer needs `gst::init` to be called first.
+
+#![recursion_limit = "1024"]
+
+pub extern crate gstreamer as gst;
+
+pub mod audio_input;
+pub mod browser;
+pub mod calibration;
+pub mod capture;
+pub mod config;
+pub mod content_providers;
+pub mod download;
+pub mod draw;
+pub mod frame;
+pub mod game;
+pub mod library;
+pub mod pitch;
+pub mod playback;
+pub mod query;
+pub mod results;
+pub mod score;
+pub mod server_interface;
+pub mod simulation;
+pub mod singer;
+pub mod song;
+pub mod timeline;
+pub mod timing;
+
+/// The errors of this crate, made with `error_chain`.
+pub mod errors {
+    use error_chain::error_chain;
+
+    error_chain!{}
+}
@@ -0,0 +1,372 @@
+//! Scans song directories and keeps a catalogue of the found songs.
+//!
+//! Parsing thousands of song files takes a while, so the catalogue is cached
+//! in an index file.  Only song files that changed since the last scan are
+//! parsed again.
+
+use crate::errors::*;
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    io::{BufRead, BufReader, BufWriter},
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use log::warn;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::config;
+use crate::content_providers::resolve_local_path;
+use crate::song;
+
+/// Bumped whenever the format of the index or the scanning of songs changes,
+/// older indexes are rebuilt.
+const INDEX_VERSION: u32 = 4;
+
+/// The metadata of a song in the library.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SongEntry {
+    /// The path of the song file.
+    pub path: PathBuf,
+    /// When the song file was modified, in seconds since the epoch.
+    pub modified: u64,
+    pub title: String,
+    pub artist: String,
+    pub genre: Option<String>,
+    pub language: Option<String>,
+    pub edition: Option<String>,
+    pub year: Option<u32>,
+    /// The audio file or url, as given in the song file.
+    pub audio: String,
+    /// Where a preview of the song should start in seconds, from `#PREVIEWSTART`.
+    pub preview_start: Option<f32>,
+    /// The time until the last note ends in seconds.
+    pub duration: f32,
+    pub duet: bool,
+    /// The local files referenced by the song file: audio, cover, background and video.
+    pub media: Vec<String>,
+    /// Files referenced by the song file that do not exist.
+    pub missing_files: Vec<String>,
+}
+
+/// A song file that could not be parsed.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct BrokenSong {
+    pub path: PathBuf,
+    /// When the song file was modified, in seconds since the epoch.
+    pub modified: u64,
+    pub error: String,
+}
+
+/// All songs found in the song directories.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Library {
+    version: u32,
+    /// The songs, ordered by artist and title.
+    pub songs: Vec<SongEntry>,
+    /// Song files that could not be parsed, they are not retried until they change.
+    pub broken: Vec<BrokenSong>,
+}
+
+impl Default for Library {
+    fn default() -> Library {
+        Library {
+            version: INDEX_VERSION,
+            songs: Vec::new(),
+            broken: Vec::new(),
+        }
+    }
+}
+
+impl Library {
+    /// Load the library from an index file.
+    ///
+    /// Returns an empty library if there is no usable index.
+    pub fn load(index_path: &Path) -> Library {
+        let library = File::open(index_path)
+            .map_err(|e| e.to_string())
+            .and_then(|file| {
+                serde_json::from_reader::<_, Library>(BufReader::new(file)).map_err(|e| e.to_string())
+            });
+        match library {
+            Ok(library) if library.version == INDEX_VERSION => library,
+            Ok(_) => Library::default(),
+            Err(e) => {
+                if index_path.exists() {
+                    warn!("ignoring library index {}: {}", index_path.display(), e);
+                }
+                Library::default()
+            }
+        }
+    }
+
+    /// Write the library to an index file.
+    pub fn save(&self, index_path: &Path) -> Result<()> {
+        if let Some(dir) = index_path.parent() {
+            fs::create_dir_all(dir)
+                .chain_err(|| format!("could not create directory {}", dir.display()))?;
+        }
+        let file = File::create(index_path)
+            .chain_err(|| format!("could not create library index {}", index_path.display()))?;
+        serde_json::to_writer(BufWriter::new(file), self).chain_err(|| "could not write library index")
+    }
+
+    /// Scan the given directories for songs.
+    ///
+    /// Song files that did not change since they were last scanned are kept,
+    /// songs that are gone are removed.
+    pub fn refresh(&mut self, directories: &[PathBuf]) {
+        let mut song_files = Vec::new();
+        let mut visited = HashSet::new();
+        for directory in directories {
+            find_song_files(directory, &mut visited, &mut song_files);
+        }
+        song_files.sort();
+        song_files.dedup();
+
+        let mut known_songs: HashMap<PathBuf, SongEntry> = self
+            .songs
+            .drain(..)
+            .map(|song| (song.path.clone(), song))
+            .collect();
+        let mut known_broken: HashMap<PathBuf, BrokenSong> = self
+            .broken
+            .drain(..)
+            .map(|song| (song.path.clone(), song))
+            .collect();
+
+        let mut songs = Vec::new();
+        let mut broken = Vec::new();
+        for path in song_files {
+            let modified = match modified(&path) {
+                Some(modified) => modified,
+                None => continue,
+            };
+            if let Some(mut song) = known_songs.remove(&path).filter(|song| song.modified == modified) {
+                // the media may have been added or removed since
+                song.missing_files = missing_files(&song.media, &path);
+                songs.push(song);
+            } else if let Some(song) = known_broken.remove(&path).filter(|song| song.modified == modified) {
+                broken.push(song);
+            } else if has_song_header(&path) {
+                match scan_song(&path, modified) {
+                    Ok(song) => songs.push(song),
+                    Err(e) => broken.push(BrokenSong {
+                        path,
+                        modified,
+                        error: e.to_string(),
+                    }),
+                }
+            }
+        }
+
+        songs.sort_by(|a, b| {
+            (a.artist.to_lowercase(), a.title.to_lowercase())
+                .cmp(&(b.artist.to_lowercase(), b.title.to_lowercase()))
+        });
+        self.songs = songs;
+        self.broken = broken;
+    }
+}
+
+/// The default place of the library index, inside the user's cache directory.
+pub fn default_index_path() -> Option<PathBuf> {
+    Some(config::user_dir("XDG_CACHE_HOME", ".cache")?.join("ascii-star").join("library.json"))
+}
+
+/// Collect all `.txt` files in `directory` and its subdirectories.
+///
+/// Directories already in `visited` are skipped, so that symbolic links
+/// pointing back up the tree are followed only once.
+fn find_song_files(directory: &Path, visited: &mut HashSet<PathBuf>, song_files: &mut Vec<PathBuf>) {
+    if let Ok(canonical) = directory.canonicalize() {
+        if !visited.insert(canonical) {
+            return;
+        }
+    }
+    let entries = match fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!("could not read song directory {}: {}", directory.display(), e);
+            return;
+        }
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            find_song_files(&path, visited, song_files);
+        } else if path
+            .extension()
+            .map(|extension| extension.eq_ignore_ascii_case("txt"))
+            .unwrap_or(false)
+        {
+            song_files.push(path);
+        }
+    }
+}
+
+/// Whether the file starts with the `#TITLE` and `#ARTIST` tags of a song file.
+///
+/// Other text files, like the licenses of songs, are no songs and not broken either.
+fn has_song_header(path: &Path) -> bool {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(_) => return false,
+    };
+    let (mut title, mut artist) = (false, false);
+    // the tags are ASCII, older song files are Latin-1
+    for line in BufReader::new(file).split(b'\n').map_while(|line| line.ok()) {
+        let line = String::from_utf8_lossy(&line);
+        let line = line.trim_start_matches('\u{feff}').trim();
+        if line.is_empty() {
+            continue;
+        }
+        let tag = match line.strip_prefix('#').and_then(|tag| tag.split_once(':')) {
+            Some((tag, value)) if !value.trim().is_empty() => tag.trim().to_ascii_uppercase(),
+            Some(_) => continue,
+            // the header ends with the first note
+            None => break,
+        };
+        title |= tag == "TITLE";
+        artist |= tag == "ARTIST";
+        if title && artist {
+            return true;
+        }
+    }
+    false
+}
+
+/// When the file was modified, in seconds since the epoch.
+fn modified(path: &Path) -> Option<u64> {
+    let modified = fs::metadata(path).and_then(|metadata| metadata.modified()).ok()?;
+    modified.duration_since(UNIX_EPOCH).ok().map(|duration| duration.as_secs())
+}
+
+/// The media of the song file at `path` that do not exist.
+fn missing_files(media: &[String], path: &Path) -> Vec<String> {
+    let song_dir = path.parent().unwrap_or_else(|| Path::new(""));
+    media
+        .iter()
+        .filter(|media| resolve_local_path(media, song_dir).is_err())
+        .cloned()
+        .collect()
+}
+
+/// Parse a song file and check the files it references.
+fn scan_song(path: &Path, modified: u64) -> Result<SongEntry> {
+    let song = song::load(path)?;
+    let header = song.header;
+    let lines = song.lines;
+
+    let is_url = |media: &str| {
+        media.starts_with("file://") || media.starts_with("http://") || media.starts_with("https://")
+    };
+    let media: Vec<String> = Some(&header.audio_path)
+        .into_iter()
+        .chain(header.cover_path.iter())
+        .chain(header.background_image_path.iter())
+        .chain(header.video_path.iter())
+        .filter(|media| !is_url(media))
+        .cloned()
+        .collect();
+    let missing_files = missing_files(&media, path);
+
+    // the song ends with the last note
+    let last_beat = lines.iter().filter_map(song::last_note_end).max().unwrap_or(0);
+    let duration = song.timing.ms_at(last_beat as f32) / 1000.0;
+
+    let preview_start = song::unknown_tag_f32(&header, "PREVIEWSTART");
+
+    Ok(SongEntry {
+        path: path.to_path_buf(),
+        modified,
+        title: header.title,
+        artist: header.artist,
+        genre: header.genre,
+        language: header.language,
+        edition: header.edition,
+        year: header.year,
+        audio: header.audio_path,
+        preview_start,
+        duration,
+        duet: song::is_duet(&lines),
+        media,
+        missing_files,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SONG: &str = "#TITLE:Song\n#ARTIST:Band\n#MP3:song.mp3\n#BPM:120\n: 0 4 0 la\nE\n";
+
+    #[test]
+    fn ignores_text_files_that_are_no_songs() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("song.txt"), SONG).unwrap();
+        fs::write(dir.path().join("song.mp3"), "").unwrap();
+        fs::write(dir.path().join("license.txt"), "You are free:\n#1 to share\n").unwrap();
+        fs::write(dir.path().join("untitled.txt"), "#ARTIST:Band\n: 0 4 0 la\n#TITLE:Song\n").unwrap();
+
+        let mut library = Library::default();
+        library.refresh(&[dir.path().to_path_buf()]);
+        assert_eq!(library.songs.len(), 1);
+        assert_eq!(library.songs[0].title, "Song");
+        assert!(library.songs[0].missing_files.is_empty());
+        assert!(library.broken.is_empty());
+    }
+
+    #[test]
+    fn keeps_unchanged_songs_and_drops_removed_ones() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.txt"), SONG).unwrap();
+        fs::write(dir.path().join("b.txt"), SONG.replace("Song", "Other")).unwrap();
+        let mut library = Library::default();
+        library.refresh(&[dir.path().to_path_buf()]);
+        assert_eq!(library.songs.len(), 2);
+
+        // a cached entry is kept as is, even if it no longer matches the file
+        for song in library.songs.iter_mut() {
+            song.genre = Some("cached".to_string());
+        }
+        fs::remove_file(dir.path().join("b.txt")).unwrap();
+        library.refresh(&[dir.path().to_path_buf()]);
+        assert_eq!(library.songs.len(), 1);
+        assert_eq!(library.songs[0].title, "Song");
+        assert_eq!(library.songs[0].genre.as_deref(), Some("cached"));
+    }
+
+    #[test]
+    fn checks_the_media_of_cached_songs_again() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("song.txt"), SONG.replace("#BPM", "#COVER:cover.jpg\n#BPM")).unwrap();
+        fs::write(dir.path().join("song.mp3"), "").unwrap();
+        let mut library = Library::default();
+        library.refresh(&[dir.path().to_path_buf()]);
+        assert_eq!(library.songs[0].media, vec!["song.mp3", "cover.jpg"]);
+        assert_eq!(library.songs[0].missing_files, vec!["cover.jpg"]);
+
+        // the song file is unchanged, but its media are not
+        fs::remove_file(dir.path().join("song.mp3")).unwrap();
+        fs::write(dir.path().join("cover.jpg"), "").unwrap();
+        library.refresh(&[dir.path().to_path_buf()]);
+        assert_eq!(library.songs[0].missing_files, vec!["song.mp3"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn follows_symbolic_link_loops_only_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let songs = dir.path().join("songs");
+        fs::create_dir(&songs).unwrap();
+        fs::write(songs.join("song.txt"), SONG).unwrap();
+        std::os::unix::fs::symlink(dir.path(), songs.join("loop")).unwrap();
+
+        let mut library = Library::default();
+        library.refresh(&[songs]);
+        assert_eq!(library.songs.len(), 1);
+    }
+}
@@ -1,40 +1,23 @@
-#![recursion_limit = "1024"]
-
-extern crate gstreamer as gst;
-
-mod content_providers;
-mod draw;
-mod pitch;
-mod server_interface;
-
-use crate::content_providers::get_url_content_provider;
-
-use std::{io::{stdout, Write}, path::PathBuf, thread, sync::{Arc, Mutex}};
-use crate::gst::{MessageView, prelude::*};
+//! The command line player, see the `ascii_star` library for the engine.
+
+use ascii_star::browser::{self, Selection};
+use ascii_star::capture::Microphone;
+use ascii_star::config::{self, Config, Theme};
+use ascii_star::errors::*;
+use ascii_star::library::{self, Library};
+use ascii_star::query::Query;
+use ascii_star::server_interface::SearchResult;
+use ascii_star::{
+    audio_input, calibration, download, game, gst, results, server_interface, simulation, song,
+};
+
+use std::{env, io::Write, path::{Path, PathBuf}};
 use clap::{App, Arg, ArgGroup};
-use termion::screen::AlternateScreen;
-use alto::{Alto, Capture, Mono};
-use pitch_calc::*;
-use glib::value::Value;
-use log::{info, error};
-
-mod errors {
-    use error_chain::error_chain;
-
-    error_chain!{}
-}
-use crate::errors::*;
+use log::warn;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 const AUTHOR: &str = env!("CARGO_PKG_AUTHORS");
 
-struct CustomData {
-    playbin: gst::Element,    // Our one and only element
-    playing: bool,            // Are we in the PLAYING state?
-    terminate: bool,          // Should we terminate execution?
-    duration: Option<gst::ClockTime>, // How long does this media last, in nanoseconds
-}
-
 fn main() {
     if let Err(ref e) = run() {
         let stderr = &mut ::std::io::stderr();
@@ -56,9 +39,6 @@ fn main() {
     }
 }
 
-const SAMPLE_RATE: u32 = 44_100;
-const FRAMES: i32 = 2048;
-
 fn run() -> Result<()> {
     env_logger::init();
 
@@ -67,8 +47,14 @@ fn run() -> Result<()> {
         .version(VERSION)
         .author(AUTHOR)
         .about("An Ultrastar song player for the command line written in rust")
-        // xor: either local or search, but not both
-        .group(ArgGroup::with_name("content_providers").args(&["local", "search"]).required(true))
+        // xor: either local or search, but not both, without any the song browser is shown
+        .group(ArgGroup::with_name("content_providers").args(&["local", "search"]))
+        // the modes do something else than playing a song, only one of them at a time
+        .group(
+            ArgGroup::with_name("modes")
+                .args(&["list-input-devices", "list-songs", "calibrate", "print-config"])
+                .conflicts_with("content_providers"),
+        )
         .args(&[
             Arg::with_name("local")
                 .value_name("TXT")
@@ -76,274 +62,322 @@ fn run() -> Result<()> {
                 .long("local")
                 .help("the song file to play"),
             Arg::with_name("search")
-                .value_name("KEYWORD")
+                .value_name("QUERY")
                 .short('s')
                 .long("search")
-                .help("a keyword to search on the server"),
+                .help("keywords and filters like artist:abba or duet:true to search on the servers"),
             Arg::with_name("play")
                 .requires("search") //<
                 .value_name("INDEX")
                 .short('p')
                 .long("play")
-                .help("index from search list to play")
+                .help("index from search list to play"),
                 // TODO: add validation (value should be an int!)
+            Arg::with_name("page")
+                .requires("search")
+                .value_name("PAGE")
+                .long("page")
+                .help("the page of the search results, starting at 1"),
+            Arg::with_name("limit")
+                .requires("search")
+                .value_name("COUNT")
+                .long("limit")
+                .help("the number of search results per page and server"),
+            Arg::with_name("sort")
+                .requires("search")
+                .value_name("FIELD")
+                .long("sort")
+                .possible_values(["artist", "title", "genre", "language"])
+                .help("what the servers sort the search results by"),
+            Arg::with_name("descending")
+                .requires("sort")
+                .long("descending")
+                .help("sort the search results in descending order"),
+            Arg::with_name("difficulty")
+                .value_name("LEVEL")
+                .short('d')
+                .long("difficulty")
+                .possible_values(["easy", "medium", "hard"])
+                .help("how exact notes have to be sung to score, defaults to medium"),
+            Arg::with_name("mic")
+                .value_name("DEVICE[#CHANNEL]")
+                .short('m')
+                .long("mic")
+                .multiple_occurrences(true)
+                .help("a capture device (or one of its channels) per player, defaults to one player on the input device"),
+            Arg::with_name("list-input-devices")
+                .long("list-input-devices")
+                .help("list the available capture devices"),
+            Arg::with_name("input-device")
+                .value_name("NAME")
+                .short('i')
+                .long("input-device")
+                .help("the capture device to use instead of the system default, a file or a frequency for those backends"),
+            Arg::with_name("input-backend")
+                .value_name("BACKEND")
+                .long("input-backend")
+                .possible_values(["openal", "gstreamer", "file", "tone"])
+                .help("where the audio of the microphones comes from, defaults to openal"),
+            Arg::with_name("sample-rate")
+                .value_name("HZ")
+                .long("sample-rate")
+                .help("the sample rate to capture with, defaults to 44100"),
+            Arg::with_name("buffer-frames")
+                .value_name("FRAMES")
+                .long("buffer-frames")
+                .help("the number of frames to detect a pitch in, defaults to 2048"),
+            Arg::with_name("library")
+                .value_name("DIR")
+                .long("library")
+                .multiple_occurrences(true)
+                .help("a directory to search for songs, including its subdirectories, defaults to songs"),
+            Arg::with_name("library-index")
+                .value_name("FILE")
+                .long("library-index")
+                .help("where to cache the scanned songs, defaults to the user's cache directory"),
+            Arg::with_name("list-songs")
+                .long("list-songs")
+                .help("scan the library and list its songs"),
+            Arg::with_name("calibrate")
+                .long("calibrate")
+                .help("measure the latency between playback and capture with clicks and save it"),
+            Arg::with_name("server")
+                .value_name("URL")
+                .long("server")
+                .multiple_occurrences(true)
+                .help("a song server to search, can be given several times, defaults to http://localhost:8080"),
+            Arg::with_name("vocals")
+                .value_name("FILE")
+                .long("vocals")
+                .help("score a WAV or FLAC recording of the vocals instead of singing, printing the hits of every note"),
+            Arg::with_name("player")
+                .value_name("PLAYER")
+                .long("player")
+                .possible_values(["gstreamer", "null"])
+                .help("how the song is played, null plays nothing and only keeps the time, defaults to gstreamer"),
+            Arg::with_name("config")
+                .value_name("FILE")
+                .short('c')
+                .long("config")
+                .help("the config file to use, defaults to config.toml in the user's config directory"),
+            Arg::with_name("print-config")
+                .long("print-config")
+                .help("print the configuration in effect, with the command line arguments applied"),
         ])
         .get_matches();
 
-    println!("Ultrastar CLI player {} by @man0lis", VERSION);
-
-    let tempfile = if let Some(keyword) = matches.value_of("search") {
-        // did we get a `play` argument as well?
-        if let Some(index) = matches.value_of("play") {
-            let index = index.parse::<usize>().chain_err(|| "index has to be an integer")?;
-            let url = server_interface::search(keyword, Some(index))?.unwrap();
-
-            Some(server_interface::download_file(url)
-                .chain_err(|| "could not download .txt file")?)
-        } else {
-            // this is an exit point!
-            server_interface::search(keyword, None)?;
-            return Ok(());
-        }
-    } else {
-        None
+    let config_path = matches.value_of("config").map(PathBuf::from).or_else(config::default_path);
+    let mut config = match &config_path {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
     };
 
-    // TODO: download text file into /tmp
-    // TODO: maybe use crate `tempfile` for this?
-    // TODO: pass this tmp path to ultrastar_txt
-
-    // get path from tempfile or command line arguments.
-    // unwrap should not fail because tempfile is none => no `search` was done => `local` argument is required
-    let song_filepath: PathBuf = match &tempfile {
-        Some(file) => PathBuf::from(file.path()),
-        None => PathBuf::from(matches.value_of("local").unwrap())
-    };
-
-    // parse txt file
-    let txt_song = ultrastar_txt::parse_txt_song(song_filepath)
-        .chain_err(|| "could not parse song file")?;
-    let header = txt_song.header;
-    let lines = txt_song.lines;
+    // the environment overrides the config file, the command line arguments override both
+    if let Ok(servers) = env::var(config::SERVERS_VARIABLE) {
+        config.servers = servers
+            .split(',')
+            .map(str::trim)
+            .filter(|server| !server.is_empty())
+            .map(String::from)
+            .collect();
+    }
+    if let Some(difficulty) = matches.value_of("difficulty") {
+        config.difficulty = difficulty.parse()?;
+    }
+    if let Some(backend) = matches.value_of("input-backend") {
+        config.capture.backend = backend.parse()?;
+    }
+    if let Some(device) = matches.value_of("input-device") {
+        config.capture.default_device = Some(device.to_string());
+    }
+    if let Some(sample_rate) = matches.value_of("sample-rate") {
+        config.capture.sample_rate = sample_rate.parse().chain_err(|| "sample rate has to be an integer")?;
+    }
+    if let Some(frames) = matches.value_of("buffer-frames") {
+        config.capture.frames = frames.parse().chain_err(|| "buffer frames have to be an integer")?;
+    }
+    if let Some(directories) = matches.values_of("library") {
+        config.library = directories.map(PathBuf::from).collect();
+    }
+    if let Some(servers) = matches.values_of("server") {
+        config.servers = servers.map(String::from).collect();
+    }
 
-    // prepare song
-    let bpms = header.bpm / 60.0 / 1000.0;
-    let gap = header.gap.unwrap_or(0.0);
+    if matches.is_present("print-config") {
+        print!("{}", config.to_toml()?);
+        // this is an exit point!
+        return Ok(());
+    }
 
-    let mut line_iter = lines.into_iter();
-    let mut current_line = line_iter.next();
-    let mut next_line = line_iter.next();
+    println!("Ultrastar CLI player {} by @man0lis", VERSION);
 
-    // construct path and uri to audio file
-    let audio_path = header.audio_path;
-    let content_provider = get_url_content_provider(&audio_path);
+    let difficulty = config.difficulty;
+    let theme = Theme::from_config(&config.theme)?;
 
-    // set up openal for capture
-    let alto = Alto::load_default().chain_err(|| "could not load openal default implementation")?;
-    let cap_dev = alto.default_capture().unwrap();
-    let mut capture: Capture<Mono<i16>> = alto.open_capture(Some(&cap_dev), SAMPLE_RATE, FRAMES)
-        .chain_err(|| "could not open default capture device")?;
+    let microphones = match matches.values_of("mic") {
+        Some(mics) => mics
+            .map(|mic| mic.parse::<Microphone>())
+            .collect::<std::result::Result<Vec<_>, _>>()?,
+        None => vec![Microphone { device: None, channel: None }],
+    };
 
-    // reference counted mutex for current deteced note
-    let detected_note = Arc::new(Mutex::new(Some(LetterOctave(Letter::C, 2))));
-    let detected_note_capture = detected_note.clone();
+    let capture_settings = &config.capture;
 
-    // thread that handels audio buffers from openal the audio buffer
-    let capture_thread = move || {
-        capture.start();
-        loop {
-            let mut samples_len = capture.samples_len();
-            let mut buffer_i16: Vec<i16> = vec![0; FRAMES as usize];
-            while samples_len < buffer_i16.len() as i32 {
-                samples_len = capture.samples_len();
-                thread::sleep(std::time::Duration::from_millis(1));
+    if matches.is_present("list-songs") {
+        let library = load_library(&matches, &config)?;
+        for song in library.broken.iter() {
+            warn!("skipped {}: {}", song.path.display(), song.error);
+        }
+        for song in library.songs.iter() {
+            let duration = song.duration.round() as u32;
+            let duet = if song.duet { " (duet)" } else { "" };
+            println!("{} - {} [{}:{:02}]{}", song.artist, song.title, duration / 60, duration % 60, duet);
+            println!("    {}", song.path.display());
+            if !song.missing_files.is_empty() {
+                println!("    missing: {}", song.missing_files.join(", "));
             }
-            capture
-                .capture_samples(&mut buffer_i16)
-                .chain_err(|| "could not capture samples")
-                .unwrap();
-            let buffer_f32: Vec<_> = buffer_i16
-                .iter()
-                .map(|x| (*x as f32) / (std::i16::MAX as f32) * 2.0)
-                .collect();
-            let max_volume = pitch::get_max_amplitude(buffer_f32.as_ref());
-            let mut dominant_note = detected_note_capture.lock().unwrap();
-            *dominant_note = if max_volume > 0.1 {
-                Some(pitch::get_dominant_note(
-                    buffer_f32.as_ref(),
-                    SAMPLE_RATE as f64,
-                ))
-            } else {
-                None
-            };
         }
-    };
-
-    // initialize GStreamer
-    gst::init().unwrap();
-
-    // create the playbin element
-    let playbin = gst::ElementFactory::make("playbin", Some("playbin"))
-        .chain_err(|| "failed to create playbin element")?;
-
-    // set the URI to play
-    let urls = content_provider.urls();
-    if let Some(url) = urls.first() {
-        playbin
-            .try_set_property("uri", &url)
-            .chain_err(|| "can't set uri property on playbin")?;
+        // this is an exit point!
+        return Ok(());
     }
 
-    // disable video and subtitle, if they exist
-    // according to: https://github.com/sdroege/gstreamer-rs/blob/4117c01ff2c9ce9b46b8f63315af4dc284788e9b/examples/src/bin/playbin.rs#L27-L35
-    let flags: Value = playbin
-        .try_property("flags")
-        .chain_err(|| "can't get playbin flags")?;
-    let flags_class = ::glib::FlagsClass::new(flags.type_()).unwrap();
-    let flags = flags_class.builder_with_value(flags).unwrap()
-        .unset_by_nick("text")
-        .unset_by_nick("video")
-        .build()
-        .unwrap();
-    playbin
-        .try_set_property_from_value("flags", &flags)
-        .chain_err(|| "can't set playbin flags")?;
-
-    println!("Playing {} by {}...\n", header.title, header.artist);
-
-    // Start playing
-    let ret = playbin.set_state(gst::State::Playing);
-    assert!(ret.is_ok());
-
-    // connect to the bus
-    let bus = playbin.bus().unwrap();
-    let mut custom_data = CustomData {
-        playbin,
-        playing: false,
-        terminate: false,
-        duration: gst::ClockTime::NONE,
-    };
-
-    thread::spawn(capture_thread);
-
-    // get access to terminal
-    //let stdin = stdin();
-    //let mut stdout = stdout();
-    let mut stdout = AlternateScreen::from(stdout());
-
-    // clear screen
-    write!(stdout, "{}", termion::clear::All).chain_err(|| "could not write to stdout")?;
-
-    // begin main loop
-    while !custom_data.terminate {
-        let msg = bus.timed_pop(10 * gst::ClockTime::MSECOND);
+    // initialize GStreamer, the song browser needs it for previews
+    gst::init().chain_err(|| "could not initialize GStreamer")?;
 
-        match msg {
-            Some(msg) => {
-                handle_message(&mut custom_data, &msg);
+    if matches.is_present("list-input-devices") {
+        let (devices, default_device) = audio_input::list_devices(config.capture.backend)?;
+        for device in devices {
+            if Some(&device) == default_device.as_ref() {
+                println!("{} (default)", device);
+            } else {
+                println!("{}", device);
             }
-            None => {
-                if custom_data.playing {
-                    let position = custom_data
-                        .playbin
-                        .query_position()
-                        .or(gst::ClockTime::NONE);
-
-                    // If we didn't know it yet, query the stream duration
-                    if custom_data.duration == gst::ClockTime::NONE {
-                        custom_data.duration = custom_data
-                            .playbin
-                            .query_duration()
-                            .or(gst::ClockTime::NONE);
-                    }
-                    // get note from capture thread
-                    let dominant_note = *detected_note.lock().unwrap();
-                    // calculate current beat
-                    let position_ms = position.map(|p| p.mseconds()).unwrap_or(0) as f32;
-                    // don't know why I need the 4.0 but its in the
-                    // original game and its not working without it
-                    let beat = (position_ms - gap) * (bpms * 4.0);
+        }
+        // this is an exit point!
+        return Ok(());
+    }
 
-                    let next_line_start = if next_line.is_some() {
-                        next_line.clone().unwrap().start
-                    } else {
-                        // last line reached, make next if always fail
-                        beat as i32 + 100
-                    };
-                    if beat > next_line_start as f32 {
-                        // reprint current line to avoid stale highlights
-                        if let &Some(ref line) = &current_line {
-                            write!(
-                                stdout,
-                                "{}",
-                                draw::generate_screen(line, beat + 100.0, dominant_note)?
-                            ).chain_err(|| "could not write to stdout")?;
-                        }
+    if matches.is_present("calibrate") {
+        println!("Measuring latency, make sure the microphone can hear the speakers...");
+        let latency_ms = calibration::measure_latency(capture_settings)?;
+        let config_path = config_path.chain_err(|| "no config directory found to save the latency in, use --config")?;
+        // save only the latency, not the command line arguments
+        let mut saved_config = Config::load(&config_path)?;
+        saved_config.latency_ms = latency_ms;
+        saved_config.save(&config_path)?;
+        println!("Latency is {} ms, saved to {}", latency_ms, config_path.display());
+        // this is an exit point!
+        return Ok(());
+    }
 
-                        if next_line.is_some() {
-                            current_line = next_line;
-                        };
-                        next_line = line_iter.next();
-                        // clear screen
-                        write!(stdout, "{}", termion::clear::All)
-                            .chain_err(|| "could not write to stdout")?;
-                    }
+    let download_dir = || download::directory(&config).chain_err(|| "no cache directory found, set download_dir in the config");
+    let song_filepath: PathBuf = if let Some(search) = matches.value_of("search") {
+        let mut query = Query::parse(search)?;
+        if let Some(page) = matches.value_of("page") {
+            query.page = Some(page.parse().chain_err(|| "page has to be an integer")?);
+        }
+        if let Some(limit) = matches.value_of("limit") {
+            query.limit = Some(limit.parse().chain_err(|| "limit has to be an integer")?);
+        }
+        // unwrap should not fail because of the possible values
+        query.sort = matches.value_of("sort").map(|sort| sort.parse().unwrap());
+        query.descending = matches.is_present("descending");
 
-                    // print current lyric line
-                    if let &Some(ref line) = &current_line {
-                        write!(
-                            stdout,
-                            "{}",
-                            draw::generate_screen(line, beat, dominant_note)?
-                        ).chain_err(|| "could not write to stdout")?;
-                    }
+        let results = server_interface::search_songs(&config.servers, &query)?;
+        // did we get a `play` argument as well?
+        if let Some(index) = matches.value_of("play") {
+            let index = index.parse::<usize>().chain_err(|| "index has to be an integer")?;
+            let song = results.get(index).chain_err(|| "index out of bounds")?;
+            download(song, &download_dir()?)?
+        } else {
+            for (i, file) in results.iter().enumerate() {
+                if let Some(genre) = file.genre.as_ref() {
+                    println!("{number:2}: {title} - {artist} ({genre})", number = i, title = file.title, artist = file.artist, genre = genre);
+                } else {
+                    println!("{number:2}: {title} - {artist}", number = i, title = file.title, artist = file.artist);
                 }
             }
+            // this is an exit point!
+            return Ok(());
         }
-    }
-    // end main loop
+    } else if let Some(path) = matches.value_of("local") {
+        PathBuf::from(path)
+    } else {
+        match browser::run(&load_library(&matches, &config)?, &config.servers)? {
+            Some(Selection::Local(path)) => path,
+            Some(Selection::Remote(song)) => download(&song, &download_dir()?)?,
+            // this is an exit point!
+            None => return Ok(()),
+        }
+    };
 
-    // Shutdown pipeline
-    let ret = custom_data.playbin.set_state(gst::State::Null);
-    assert!(ret.is_ok());
+    if let Some(vocals) = matches.value_of("vocals") {
+        let song = song::load(&song_filepath).chain_err(|| "could not parse song file")?;
+        let recording = audio_input::RecordedInput::open(Path::new(vocals), capture_settings.frames, false)?;
+        for (track, lines) in song::split_tracks(&song.lines).iter().enumerate() {
+            let mut input = recording.clone();
+            let scorer = simulation::score_input(lines, &song.timing, &mut input, capture_settings, difficulty)?;
+            println!();
+            println!("P{}", track + 1);
+            print!("{}", results::Results::new(&scorer, lines).to_plain_text());
+            println!();
+            print!("{}", simulation::note_report(&scorer, lines));
+        }
+        // this is an exit point!
+        return Ok(());
+    }
 
-    println!();
+    let settings = game::Settings {
+        microphones,
+        capture: config.capture.clone(),
+        difficulty,
+        theme,
+        latency_ms: config.latency_ms,
+        null_player: matches.value_of("player") == Some("null"),
+    };
+    let session = game::Session::load(&song_filepath, &settings)?;
+    println!("Playing {} by {}...\n", session.header().title, session.header().artist);
+    let results = game::play(session)?;
+
+    // the terminal is left, so the results stay in the scrollback
+    for (name, singer_results) in results.iter() {
+        println!();
+        println!("{}", name);
+        print!("{}", singer_results.to_plain_text());
+    }
     Ok(())
 }
 
-fn handle_message(custom_data: &mut CustomData, msg: &gst::MessageRef) {
-    match msg.view() {
-        MessageView::Error(err) => {
-            error!(
-                "Error received from element {:?}: {} ({:?})",
-                msg.src().map(|s| s.path_string()),
-                err.error(),
-                err.debug()
-            );
-            custom_data.terminate = true;
+/// Download a song found on a server, showing the progress of every file.
+fn download(song: &SearchResult, download_dir: &Path) -> Result<PathBuf> {
+    let mut shown = String::new();
+    let mut show_progress = |progress: &download::Progress| {
+        let bar = progress.bar();
+        if bar != shown {
+            print!("\r{}", bar);
+            let _ = std::io::stdout().flush();
+            shown = bar;
         }
-        MessageView::Eos(..) => {
-            info!("End-Of-Stream reached.");
-            custom_data.terminate = true;
+        if progress.finished {
+            println!();
+            shown.clear();
         }
-        MessageView::DurationChanged(_) => {
-            // The duration has changed, mark the current one as invalid
-            custom_data.duration = gst::ClockTime::NONE;
-        }
-        MessageView::StateChanged(state) => if msg.src()
-            .map(|s| s == custom_data.playbin)
-            .unwrap_or(false)
-        {
-            let new_state = state.current();
-            let old_state = state.old();
+    };
+    download::download_song(song, download_dir, &mut show_progress).chain_err(|| "could not download the song")
+}
 
-            info!(
-                "Pipeline state changed from {:?} to {:?}",
-                old_state, new_state
-            );
+/// Scan the song directories of the config and the downloaded songs, using the cached index.
+fn load_library(matches: &clap::ArgMatches, config: &Config) -> Result<Library> {
+    let index_path = match matches.value_of("library-index") {
+        Some(path) => PathBuf::from(path),
+        None => library::default_index_path().chain_err(|| "no cache directory found, use --library-index")?,
+    };
 
-            custom_data.playing = new_state == gst::State::Playing;
-        },
-        _ => (),
-    }
+    let mut directories = config.library.clone();
+    directories.extend(download::directory(config).filter(|dir| dir.is_dir()));
+    let mut library = Library::load(&index_path);
+    library.refresh(&directories);
+    library.save(&index_path)?;
+    Ok(library)
 }
+
@@ -1,49 +1,278 @@
+//! Detects the pitch of sung notes using the YIN algorithm.
+//!
+//! See "YIN, a fundamental frequency estimator for speech and music" by
+//! de Cheveigné and Kawahara (2002).
+
+use std::time::Instant;
+
 use pitch_calc::*;
 
-fn do_autocorrelation_with_freq(samples: &[f32], sample_rate: f64, freq: f64) -> f64 {
-    let samples_per_period = (sample_rate / freq).round() as usize;
-    let correlating_sample_iter = samples.iter().skip(samples_per_period);
-    let sample_zipped_iter = samples.iter().zip(correlating_sample_iter);
-    let accum_dist = sample_zipped_iter.fold(0.0, |acc, (x, y)| acc + (x - y).abs());
-    1.0 - accum_dist as f64 / samples.len() as f64
-}
-
-fn get_note_wieghts(samples: &[f32], sample_rate: f64) -> Vec<(LetterOctave, f64)> {
-    let first_tone = LetterOctave(Letter::C, 2);
-    let last_tone = LetterOctave(Letter::A, 5);
-
-    let first_semitone = first_tone.to_step().step() as i32;
-    let last_semitone = last_tone.to_step().step() as i32;
-
-    (first_semitone..last_semitone)
-        .map(|step| {
-            let step_float = step as f32;
-            (
-                Step(step_float).to_letter_octave(),
-                do_autocorrelation_with_freq(
-                    samples,
-                    sample_rate,
-                    Step(step_float).to_hz().hz() as f64,
-                ),
-            )
+/// The lowest frequency that is detected (about B1).
+const MIN_FREQUENCY: f64 = 60.0;
+/// The highest frequency that is detected (about C#6).
+const MAX_FREQUENCY: f64 = 1100.0;
+/// Dips of the normalized difference below this value are considered periodic.
+const YIN_THRESHOLD: f64 = 0.15;
+
+/// An estimated fundamental frequency.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Estimate {
+    /// The fundamental frequency in Hz.
+    pub frequency: f64,
+    /// How periodic the signal is, from 0 (noise) to 1 (perfectly periodic).
+    pub clarity: f64,
+}
+
+/// The pitch detected in a buffer of samples.
+#[derive(Clone, Copy, Debug)]
+pub struct PitchReading {
+    /// The fundamental frequency in Hz.
+    pub frequency: f64,
+    /// The nearest semitone.
+    pub step: Step,
+    /// How far the frequency is off `step`, from -50 (flat) to 50 (sharp).
+    pub cents: f32,
+    /// How periodic the signal is, from 0 (noise) to 1 (perfectly periodic).
+    pub clarity: f64,
+    /// The root mean square level of the samples.
+    pub rms: f32,
+    /// When the samples were captured.
+    pub timestamp: Instant,
+}
+
+impl PitchReading {
+    /// Detect the pitch of the given samples captured at `timestamp`.
+    pub fn from_samples(
+        samples: &[f32],
+        sample_rate: f64,
+        timestamp: Instant,
+    ) -> Option<PitchReading> {
+        let estimate = estimate_frequency(samples, sample_rate)?;
+        let exact_step = hz_to_step(estimate.frequency);
+        let nearest_step = exact_step.round();
+
+        Some(PitchReading {
+            frequency: estimate.frequency,
+            step: Step(nearest_step),
+            cents: (exact_step - nearest_step) * 100.0,
+            clarity: estimate.clarity,
+            rms: get_rms(samples),
+            timestamp,
         })
-        .collect::<Vec<_>>()
+    }
+
+    /// The detected pitch as step including the cents offset.
+    pub fn exact_step(&self) -> f32 {
+        self.step.step() + self.cents / 100.0
+    }
+
+    /// The nearest note.
+    pub fn note(&self) -> LetterOctave {
+        self.step.to_letter_octave()
+    }
 }
 
-pub fn get_dominant_note(samples: &[f32], sample_rate: f64) -> LetterOctave {
-    get_note_wieghts(samples, sample_rate)
-        .iter()
-        .fold(
-            (LetterOctave(Letter::C, 2), -1.0),
-            |(old_note, old_max_wight), &(note, weight)| if weight > old_max_wight {
-                (note, weight)
-            } else {
-                (old_note, old_max_wight)
-            },
-        )
-        .0
+/// The number of samples needed to detect the lowest frequency.
+pub fn min_samples(sample_rate: f64) -> usize {
+    2 * (sample_rate / MIN_FREQUENCY).ceil() as usize
 }
 
-pub fn get_max_amplitude(samples: &[f32]) -> f32 {
-    samples.iter().map(|x| x.abs()).fold(0.0, f32::max)
+/// Estimate the fundamental frequency of the given samples.
+///
+/// Returns `None` if the buffer is too short or if no period was found.
+pub fn estimate_frequency(samples: &[f32], sample_rate: f64) -> Option<Estimate> {
+    let min_period = (sample_rate / MAX_FREQUENCY).floor() as usize;
+    let max_period = (sample_rate / MIN_FREQUENCY).ceil() as usize;
+    if min_period < 2 || samples.len() < min_samples(sample_rate) {
+        return None;
+    }
+    let window = samples.len() - max_period;
+
+    let differences = cumulative_mean_normalized_difference(samples, window, max_period);
+
+    // take the first dip below the threshold, or the global minimum otherwise
+    let period = match (min_period..max_period).find(|&tau| differences[tau] < YIN_THRESHOLD) {
+        Some(mut tau) => {
+            while tau + 1 < max_period && differences[tau + 1] < differences[tau] {
+                tau += 1;
+            }
+            tau
+        }
+        None => (min_period..max_period).min_by(|&a, &b| {
+            differences[a]
+                .partial_cmp(&differences[b])
+                .unwrap_or(std::cmp::Ordering::Equal)
+        })?,
+    };
+
+    let clarity = (1.0 - differences[period]).max(0.0);
+    let refined_period = parabolic_interpolation(&differences, period);
+
+    Some(Estimate {
+        frequency: sample_rate / refined_period,
+        clarity,
+    })
+}
+
+/// Step two and three of YIN: the difference function, normalized by its
+/// cumulative mean.
+fn cumulative_mean_normalized_difference(
+    samples: &[f32],
+    window: usize,
+    max_period: usize,
+) -> Vec<f64> {
+    let mut differences = vec![1.0; max_period + 1];
+    let mut running_sum = 0.0;
+    for tau in 1..=max_period {
+        let difference: f64 = samples[..window]
+            .iter()
+            .zip(samples[tau..tau + window].iter())
+            .map(|(x, y)| {
+                let delta = (x - y) as f64;
+                delta * delta
+            })
+            .sum();
+        running_sum += difference;
+        differences[tau] = if running_sum > 0.0 {
+            difference * tau as f64 / running_sum
+        } else {
+            1.0
+        };
+    }
+    differences
+}
+
+/// Refine the minimum at `tau` by fitting a parabola through its neighbours.
+fn parabolic_interpolation(values: &[f64], tau: usize) -> f64 {
+    if tau == 0 || tau + 1 >= values.len() {
+        return tau as f64;
+    }
+    let (left, center, right) = (values[tau - 1], values[tau], values[tau + 1]);
+    let denominator = left - 2.0 * center + right;
+    if denominator.abs() < f64::EPSILON {
+        tau as f64
+    } else {
+        tau as f64 + 0.5 * (left - right) / denominator
+    }
+}
+
+/// Convert a frequency to a (fractional) step.
+fn hz_to_step(frequency: f64) -> f32 {
+    let a4 = LetterOctave(Letter::A, 4);
+    let semitones = 12.0 * (frequency / a4.to_hz().hz() as f64).log2();
+    a4.to_step().step() + semitones as f32
+}
+
+/// The root mean square, the level of the samples.
+pub fn get_rms(samples: &[f32]) -> f32 {
+    if samples.is_empty() {
+        return 0.0;
+    }
+    (samples.iter().map(|x| x * x).sum::<f32>() / samples.len() as f32).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::f64::consts::PI;
+
+    const SAMPLE_RATE: f64 = 44_100.0;
+    const FRAMES: usize = 2048;
+
+    fn sine(frequency: f64) -> Vec<f32> {
+        (0..FRAMES)
+            .map(|i| (2.0 * PI * frequency * i as f64 / SAMPLE_RATE).sin() as f32 * 0.5)
+            .collect()
+    }
+
+    fn sawtooth(frequency: f64) -> Vec<f32> {
+        (0..FRAMES)
+            .map(|i| {
+                let phase = (frequency * i as f64 / SAMPLE_RATE).fract();
+                (2.0 * phase - 1.0) as f32 * 0.5
+            })
+            .collect()
+    }
+
+    /// A sine whose frequency oscillates by `depth` cents with `rate` Hz.
+    fn vibrato(frequency: f64, depth: f64, rate: f64) -> Vec<f32> {
+        let mut phase = 0.0;
+        (0..FRAMES)
+            .map(|i| {
+                let t = i as f64 / SAMPLE_RATE;
+                let cents = depth * (2.0 * PI * rate * t).sin();
+                phase += frequency * 2f64.powf(cents / 1200.0) / SAMPLE_RATE;
+                (2.0 * PI * phase).sin() as f32 * 0.5
+            })
+            .collect()
+    }
+
+    fn get_dominant_note(samples: &[f32]) -> Option<LetterOctave> {
+        PitchReading::from_samples(samples, SAMPLE_RATE, Instant::now()).map(|reading| reading.note())
+    }
+
+    fn assert_close(estimate: Option<Estimate>, frequency: f64) {
+        let estimate = estimate.expect("no frequency detected");
+        let cents = 1200.0 * (estimate.frequency / frequency).log2();
+        assert!(
+            cents.abs() < 10.0,
+            "detected {} Hz instead of {} Hz",
+            estimate.frequency,
+            frequency
+        );
+    }
+
+    #[test]
+    fn detects_sine() {
+        for &frequency in &[82.41, 220.0, 440.0, 880.0] {
+            assert_close(estimate_frequency(&sine(frequency), SAMPLE_RATE), frequency);
+        }
+        assert_eq!(
+            get_dominant_note(&sine(220.0)),
+            Some(LetterOctave(Letter::A, 3))
+        );
+    }
+
+    #[test]
+    fn detects_sawtooth_without_octave_errors() {
+        for &frequency in &[110.0, 261.63, 523.25] {
+            assert_close(estimate_frequency(&sawtooth(frequency), SAMPLE_RATE), frequency);
+        }
+        assert_eq!(
+            get_dominant_note(&sawtooth(440.0)),
+            Some(LetterOctave(Letter::A, 4))
+        );
+    }
+
+    #[test]
+    fn detects_vibrato() {
+        assert_eq!(
+            get_dominant_note(&vibrato(329.63, 30.0, 5.5)),
+            Some(LetterOctave(Letter::E, 4))
+        );
+    }
+
+    #[test]
+    fn reading_reports_cents() {
+        // 20 cents sharp of A4
+        let frequency = 440.0 * 2f64.powf(20.0 / 1200.0);
+        let reading = PitchReading::from_samples(&sine(frequency), SAMPLE_RATE, Instant::now())
+            .expect("no frequency detected");
+        assert_eq!(reading.note(), LetterOctave(Letter::A, 4));
+        assert!((reading.cents - 20.0).abs() < 5.0, "{} cents", reading.cents);
+        assert!(reading.clarity > 0.9);
+        assert!((reading.rms - 0.5 / 2f32.sqrt()).abs() < 0.01);
+    }
+
+    #[test]
+    fn silence_is_not_periodic() {
+        let estimate = estimate_frequency(&[0.0; FRAMES], SAMPLE_RATE);
+        assert!(estimate.map(|estimate| estimate.clarity < 0.5).unwrap_or(true));
+    }
+
+    #[test]
+    fn short_buffers_are_rejected() {
+        assert_eq!(estimate_frequency(&sine(440.0)[..512], SAMPLE_RATE), None);
+    }
 }
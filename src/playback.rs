@@ -0,0 +1,487 @@
+//! Plays the audio of songs.
+//!
+//! Songs are played by a `Player`, which is GStreamer unless nothing should
+//! be heard: the null player only advances a clock, to sing along in
+//! headless environments and to drive the game loop in tests.
+
+use crate::errors::*;
+
+use std::{
+    collections::VecDeque,
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::gst::{prelude::*, MessageView};
+use glib::value::Value;
+use log::{error, info};
+
+/// How long a preview plays.
+const PREVIEW_LENGTH: Duration = Duration::from_secs(15);
+/// How long a preview takes to reach full volume.
+const PREVIEW_FADE_IN: Duration = Duration::from_millis(1500);
+/// How long a preview takes to become silent at its end.
+const PREVIEW_FADE_OUT: Duration = Duration::from_millis(1000);
+
+/// Create a playbin that plays the audio of the given uri.
+///
+/// GStreamer has to be initialized already.
+pub fn create_playbin(url: &str) -> Result<gst::Element> {
+    // create the playbin element
+    let playbin = gst::ElementFactory::make("playbin", Some("playbin"))
+        .chain_err(|| "failed to create playbin element")?;
+
+    // set the URI to play
+    playbin
+        .try_set_property("uri", url)
+        .chain_err(|| "can't set uri property on playbin")?;
+
+    // disable video and subtitle, if they exist
+    // according to: https://github.com/sdroege/gstreamer-rs/blob/4117c01ff2c9ce9b46b8f63315af4dc284788e9b/examples/src/bin/playbin.rs#L27-L35
+    let flags: Value = playbin
+        .try_property("flags")
+        .chain_err(|| "can't get playbin flags")?;
+    let flags_class = ::glib::FlagsClass::new(flags.type_()).unwrap();
+    let flags = flags_class.builder_with_value(flags).unwrap()
+        .unset_by_nick("text")
+        .unset_by_nick("video")
+        .build()
+        .unwrap();
+    playbin
+        .try_set_property_from_value("flags", &flags)
+        .chain_err(|| "can't set playbin flags")?;
+
+    Ok(playbin)
+}
+
+/// What happened to the playback of a song.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PlayerEvent {
+    /// The song was started or paused.
+    StateChanged { playing: bool },
+    /// The end of the song was reached.
+    EndOfStream,
+    /// The song can't be played any further.
+    Error(String),
+}
+
+/// Plays the audio of a song, positions are in ms.
+pub trait Player {
+    /// Load the song at `url`, paused at its start.
+    fn load(&mut self, url: &str) -> Result<()>;
+
+    fn play(&mut self) -> Result<()>;
+
+    fn pause(&mut self) -> Result<()>;
+
+    fn seek(&mut self, position_ms: f32) -> Result<()>;
+
+    /// The position of the playback, `None` if it is not known yet.
+    fn position(&self) -> Option<f32>;
+
+    /// The length of the song, `None` if it is not known yet.
+    fn duration(&self) -> Option<f32>;
+
+    /// Wait up to `timeout` for something to happen to the playback.
+    fn next_event(&mut self, timeout: Duration) -> Option<PlayerEvent>;
+}
+
+/// Plays songs with a GStreamer playbin.
+#[derive(Default)]
+pub struct GStreamerPlayer {
+    playbin: Option<gst::Element>,
+    bus: Option<gst::Bus>,
+}
+
+impl GStreamerPlayer {
+    /// Create a player without a song, GStreamer has to be initialized already.
+    pub fn new() -> GStreamerPlayer {
+        GStreamerPlayer::default()
+    }
+
+    fn playbin(&self) -> Result<&gst::Element> {
+        self.playbin.as_ref().chain_err(|| "no song loaded")
+    }
+}
+
+impl Player for GStreamerPlayer {
+    fn load(&mut self, url: &str) -> Result<()> {
+        if let Some(playbin) = self.playbin.take() {
+            let _ = playbin.set_state(gst::State::Null);
+        }
+        let playbin = create_playbin(url)?;
+        self.bus = Some(playbin.bus().chain_err(|| "the playbin has no bus")?);
+        self.playbin = Some(playbin);
+        Ok(())
+    }
+
+    fn play(&mut self) -> Result<()> {
+        self.playbin()?
+            .set_state(gst::State::Playing)
+            .chain_err(|| "could not play the song")?;
+        Ok(())
+    }
+
+    fn pause(&mut self) -> Result<()> {
+        self.playbin()?
+            .set_state(gst::State::Paused)
+            .chain_err(|| "could not pause the song")?;
+        Ok(())
+    }
+
+    fn seek(&mut self, position_ms: f32) -> Result<()> {
+        let playbin = self.playbin()?;
+        // seeking needs the pipeline to be prerolled
+        if matches!(playbin.current_state(), gst::State::Null | gst::State::Ready) {
+            playbin.set_state(gst::State::Paused).chain_err(|| "could not load the song")?;
+            let (state_change, _, _) = playbin.state(5 * gst::ClockTime::SECOND);
+            state_change.chain_err(|| "could not load the song")?;
+        }
+        playbin
+            .seek_simple(
+                gst::SeekFlags::FLUSH | gst::SeekFlags::ACCURATE,
+                gst::ClockTime::from_mseconds(position_ms.max(0.0) as u64),
+            )
+            .chain_err(|| "could not seek in the song")?;
+        Ok(())
+    }
+
+    fn position(&self) -> Option<f32> {
+        let position = self.playbin.as_ref()?.query_position::<gst::ClockTime>()?;
+        Some(position.mseconds() as f32)
+    }
+
+    fn duration(&self) -> Option<f32> {
+        let duration = self.playbin.as_ref()?.query_duration::<gst::ClockTime>()?;
+        Some(duration.mseconds() as f32)
+    }
+
+    fn next_event(&mut self, timeout: Duration) -> Option<PlayerEvent> {
+        let bus = match self.bus.as_ref() {
+            Some(bus) => bus,
+            None => {
+                thread::sleep(timeout);
+                return None;
+            }
+        };
+        let msg = bus.timed_pop(gst::ClockTime::from_mseconds(timeout.as_millis() as u64))?;
+        match msg.view() {
+            MessageView::Error(err) => {
+                error!(
+                    "Error received from element {:?}: {} ({:?})",
+                    msg.src().map(|s| s.path_string()),
+                    err.error(),
+                    err.debug()
+                );
+                Some(PlayerEvent::Error(err.error().to_string()))
+            }
+            MessageView::Eos(..) => {
+                info!("End-Of-Stream reached.");
+                Some(PlayerEvent::EndOfStream)
+            }
+            MessageView::StateChanged(state) => {
+                let from_playbin = msg
+                    .src()
+                    .map(|s| self.playbin.as_ref().map(|playbin| s == *playbin).unwrap_or(false))
+                    .unwrap_or(false);
+                if !from_playbin {
+                    return None;
+                }
+                info!("Pipeline state changed from {:?} to {:?}", state.old(), state.current());
+                Some(PlayerEvent::StateChanged {
+                    playing: state.current() == gst::State::Playing,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Drop for GStreamerPlayer {
+    fn drop(&mut self) {
+        if let Some(playbin) = self.playbin.as_ref() {
+            let _ = playbin.set_state(gst::State::Null);
+        }
+    }
+}
+
+/// Plays nothing, but advances a virtual clock while playing.
+///
+/// In `realtime`, the clock follows the wall clock from when the song was
+/// started or seeked, so time spent between the calls to `next_event` is not
+/// lost.  Otherwise it advances by the timeout of every `next_event` call
+/// without sleeping, so songs can be played as fast as they are checked.
+pub struct NullPlayer {
+    duration_ms: Option<f32>,
+    /// The position, or where the clock started in realtime.
+    position_ms: Option<f32>,
+    /// When the clock started in realtime, `None` while paused.
+    started: Option<Instant>,
+    playing: bool,
+    realtime: bool,
+    events: VecDeque<PlayerEvent>,
+}
+
+impl NullPlayer {
+    /// Create a player for songs of the given length, endless without one.
+    pub fn new(duration_ms: Option<f32>, realtime: bool) -> NullPlayer {
+        NullPlayer {
+            duration_ms,
+            position_ms: None,
+            started: None,
+            playing: false,
+            realtime,
+            events: VecDeque::new(),
+        }
+    }
+
+    fn set_playing(&mut self, playing: bool) -> Result<()> {
+        if self.position_ms.is_none() {
+            return Err("no song loaded".into());
+        }
+        if self.playing != playing {
+            // keep the position the clock reached
+            self.position_ms = self.position();
+            self.started = if playing && self.realtime { Some(Instant::now()) } else { None };
+            self.playing = playing;
+            self.events.push_back(PlayerEvent::StateChanged { playing });
+        }
+        Ok(())
+    }
+
+    /// Limit a position to the length of the song.
+    fn clamp(&self, position_ms: f32) -> f32 {
+        let position_ms = position_ms.max(0.0);
+        self.duration_ms.map(|duration| position_ms.min(duration)).unwrap_or(position_ms)
+    }
+}
+
+impl Player for NullPlayer {
+    fn load(&mut self, _url: &str) -> Result<()> {
+        self.position_ms = Some(0.0);
+        self.started = None;
+        self.playing = false;
+        self.events.clear();
+        Ok(())
+    }
+
+    fn play(&mut self) -> Result<()> {
+        self.set_playing(true)
+    }
+
+    fn pause(&mut self) -> Result<()> {
+        self.set_playing(false)
+    }
+
+    fn seek(&mut self, position_ms: f32) -> Result<()> {
+        self.position_ms = Some(self.clamp(position_ms));
+        if self.started.is_some() {
+            self.started = Some(Instant::now());
+        }
+        Ok(())
+    }
+
+    fn position(&self) -> Option<f32> {
+        let position_ms = self.position_ms?;
+        Some(match self.started {
+            Some(started) => self.clamp(position_ms + started.elapsed().as_secs_f32() * 1000.0),
+            None => position_ms,
+        })
+    }
+
+    fn duration(&self) -> Option<f32> {
+        self.duration_ms
+    }
+
+    fn next_event(&mut self, timeout: Duration) -> Option<PlayerEvent> {
+        if let Some(event) = self.events.pop_front() {
+            return Some(event);
+        }
+        if self.realtime {
+            thread::sleep(timeout);
+        } else if self.playing {
+            let position = self.position_ms.unwrap_or(0.0) + timeout.as_secs_f32() * 1000.0;
+            self.position_ms = Some(self.clamp(position));
+        }
+        if self.playing && self.duration_ms.is_some() && self.position() >= self.duration_ms {
+            self.position_ms = self.duration_ms;
+            self.started = None;
+            self.playing = false;
+            return Some(PlayerEvent::EndOfStream);
+        }
+        None
+    }
+}
+
+/// A short clip of a song that fades in, stopped when dropped.
+pub struct Preview {
+    playbin: gst::Element,
+    bus: gst::Bus,
+    /// Where the preview starts, until the song is loaded and seeked to it.
+    pending_seek: Option<Duration>,
+    /// When the preview started playing, `None` while the song is loading.
+    started: Option<Instant>,
+}
+
+impl Preview {
+    /// Start loading the song at `url`, to play it from `start` on.
+    ///
+    /// Returns right away, the preview starts playing in `update` once the
+    /// song is loaded.
+    pub fn start(url: &str, start: Duration) -> Result<Preview> {
+        let playbin = create_playbin(url)?;
+        playbin
+            .try_set_property("volume", 0.0f64)
+            .chain_err(|| "can't set volume of playbin")?;
+        let bus = playbin.bus().chain_err(|| "the playbin has no bus")?;
+
+        // seeking needs the pipeline to be prerolled, which is announced on the bus
+        playbin
+            .set_state(gst::State::Paused)
+            .chain_err(|| "could not pause preview")?;
+
+        Ok(Preview {
+            playbin,
+            bus,
+            pending_seek: Some(start),
+            started: None,
+        })
+    }
+
+    /// Start the loaded preview and fade the volume in or out, call this regularly.
+    ///
+    /// Returns whether the preview is still loading or playing.
+    pub fn update(&mut self) -> bool {
+        while let Some(msg) = self.bus.timed_pop(gst::ClockTime::ZERO) {
+            match msg.view() {
+                MessageView::AsyncDone(..) => {
+                    if let Some(start) = self.pending_seek.take() {
+                        if self.play_from(start).is_err() {
+                            return self.stop();
+                        }
+                    }
+                }
+                MessageView::Error(..) | MessageView::Eos(..) => return self.stop(),
+                _ => {}
+            }
+        }
+
+        let elapsed = match self.started {
+            Some(started) => started.elapsed(),
+            None => return true,
+        };
+        if elapsed >= PREVIEW_LENGTH {
+            return self.stop();
+        }
+        let fade_in = elapsed.as_secs_f64() / PREVIEW_FADE_IN.as_secs_f64();
+        let fade_out = (PREVIEW_LENGTH - elapsed).as_secs_f64() / PREVIEW_FADE_OUT.as_secs_f64();
+        let volume = fade_in.min(fade_out).min(1.0);
+        // a failed fade is not worth stopping the preview for
+        let _ = self.playbin.try_set_property("volume", volume);
+        true
+    }
+
+    /// Seek the prerolled song to `start` and play it.
+    fn play_from(&mut self, start: Duration) -> Result<()> {
+        self.playbin
+            .seek_simple(
+                gst::SeekFlags::FLUSH | gst::SeekFlags::KEY_UNIT,
+                gst::ClockTime::from_mseconds(start.as_millis() as u64),
+            )
+            .chain_err(|| "could not seek to the preview start")?;
+        self.playbin
+            .set_state(gst::State::Playing)
+            .chain_err(|| "could not start preview")?;
+        self.started = Some(Instant::now());
+        Ok(())
+    }
+
+    fn stop(&mut self) -> bool {
+        let _ = self.playbin.set_state(gst::State::Null);
+        false
+    }
+}
+
+impl Drop for Preview {
+    fn drop(&mut self) {
+        let _ = self.playbin.set_state(gst::State::Null);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TICK: Duration = Duration::from_millis(10);
+
+    #[test]
+    fn null_player_advances_while_playing() {
+        let mut player = NullPlayer::new(Some(25.0), false);
+        assert!(player.play().is_err());
+        player.load("file:///song.mp3").unwrap();
+        assert_eq!(player.position(), Some(0.0));
+
+        // paused, the clock stands still
+        assert_eq!(player.next_event(TICK), None);
+        assert_eq!(player.position(), Some(0.0));
+
+        player.play().unwrap();
+        assert_eq!(player.next_event(TICK), Some(PlayerEvent::StateChanged { playing: true }));
+        assert_eq!(player.next_event(TICK), None);
+        assert_eq!(player.position(), Some(10.0));
+
+        player.pause().unwrap();
+        assert_eq!(player.next_event(TICK), Some(PlayerEvent::StateChanged { playing: false }));
+        assert_eq!(player.next_event(TICK), None);
+        assert_eq!(player.position(), Some(10.0));
+
+        player.play().unwrap();
+        assert_eq!(player.next_event(TICK), Some(PlayerEvent::StateChanged { playing: true }));
+        assert_eq!(player.next_event(TICK), None);
+        assert_eq!(player.next_event(TICK), Some(PlayerEvent::EndOfStream));
+        assert_eq!(player.position(), Some(25.0));
+    }
+
+    #[test]
+    fn null_player_seeks_within_the_song() {
+        let mut player = NullPlayer::new(Some(1000.0), false);
+        player.load("file:///song.mp3").unwrap();
+        player.seek(400.0).unwrap();
+        assert_eq!(player.position(), Some(400.0));
+        player.seek(-5.0).unwrap();
+        assert_eq!(player.position(), Some(0.0));
+        player.seek(5000.0).unwrap();
+        assert_eq!(player.position(), Some(1000.0));
+    }
+
+    #[test]
+    fn null_player_keeps_the_time_in_realtime() {
+        let mut player = NullPlayer::new(Some(1000.0), true);
+        player.load("file:///song.mp3").unwrap();
+        player.play().unwrap();
+        assert_eq!(player.next_event(TICK), Some(PlayerEvent::StateChanged { playing: true }));
+
+        // the time spent between the events counts as well
+        let start = Instant::now();
+        for _ in 0..5 {
+            assert_eq!(player.next_event(TICK), None);
+            thread::sleep(TICK);
+        }
+        // only a lower bound, a busy machine may sleep for longer
+        let elapsed = start.elapsed().as_secs_f32() * 1000.0;
+        let position = player.position().unwrap();
+        assert!(position >= elapsed, "{} ms after {} ms", position, elapsed);
+
+        player.pause().unwrap();
+        let paused = player.position().unwrap();
+        assert_eq!(player.next_event(TICK), Some(PlayerEvent::StateChanged { playing: false }));
+        assert_eq!(player.next_event(TICK), None);
+        assert_eq!(player.position(), Some(paused));
+
+        player.seek(990.0).unwrap();
+        player.play().unwrap();
+        assert_eq!(player.next_event(TICK), Some(PlayerEvent::StateChanged { playing: true }));
+        assert_eq!(player.next_event(TICK), Some(PlayerEvent::EndOfStream));
+        assert_eq!(player.position(), Some(1000.0));
+    }
+}
@@ -0,0 +1,202 @@
+//! Search queries for the song servers.
+//!
+//! A query is typed as keywords and filters like `artist:abba` or
+//! `title:"dancing queen"`, it is sent to the servers as query parameters.
+
+use crate::errors::*;
+
+use std::str::FromStr;
+
+/// What the servers sort the songs by.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SortField {
+    Artist,
+    Title,
+    Genre,
+    Language,
+}
+
+impl SortField {
+    fn as_str(&self) -> &'static str {
+        match self {
+            SortField::Artist => "artist",
+            SortField::Title => "title",
+            SortField::Genre => "genre",
+            SortField::Language => "language",
+        }
+    }
+}
+
+impl FromStr for SortField {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<SortField, String> {
+        match s {
+            "artist" => Ok(SortField::Artist),
+            "title" => Ok(SortField::Title),
+            "genre" => Ok(SortField::Genre),
+            "language" => Ok(SortField::Language),
+            _ => Err(format!("unknown sort field `{}`", s)),
+        }
+    }
+}
+
+/// A search for songs on the servers.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Query {
+    /// The words to search for anywhere.
+    pub keywords: Vec<String>,
+    pub artist: Option<String>,
+    pub title: Option<String>,
+    pub genre: Option<String>,
+    pub language: Option<String>,
+    /// Only duets or only songs for a single singer.
+    pub duet: Option<bool>,
+    /// The page of the results, starting at 1.
+    pub page: Option<u32>,
+    /// The number of results per page.
+    pub limit: Option<u32>,
+    pub sort: Option<SortField>,
+    pub descending: bool,
+}
+
+impl Query {
+    /// Parse keywords and filters like `artist:abba`, quotes keep words together.
+    ///
+    /// Words with a colon that is not a known filter are kept as keywords.
+    pub fn parse(input: &str) -> Result<Query> {
+        let mut query = Query::default();
+        for term in split_terms(input) {
+            let (key, value) = match term.split_once(':') {
+                Some((key, value)) if !value.is_empty() => (key.to_lowercase(), value.to_string()),
+                _ => {
+                    query.keywords.push(term);
+                    continue;
+                }
+            };
+            match key.as_str() {
+                "artist" => query.artist = Some(value),
+                "title" => query.title = Some(value),
+                "genre" => query.genre = Some(value),
+                "language" => query.language = Some(value),
+                "duet" => {
+                    query.duet = Some(match value.to_lowercase().as_str() {
+                        "true" | "yes" => true,
+                        "false" | "no" => false,
+                        _ => return Err(format!("duet has to be true or false, not \"{}\"", value).into()),
+                    })
+                }
+                _ => query.keywords.push(term),
+            }
+        }
+        Ok(query)
+    }
+
+    /// Whether there is anything to search for.
+    pub fn is_empty(&self) -> bool {
+        self.keywords.is_empty()
+            && self.artist.is_none()
+            && self.title.is_none()
+            && self.genre.is_none()
+            && self.language.is_none()
+            && self.duet.is_none()
+    }
+
+    /// The query parameters sent to the servers, encoded by the http client.
+    pub fn parameters(&self) -> Vec<(&'static str, String)> {
+        let mut parameters = Vec::new();
+        if !self.keywords.is_empty() {
+            parameters.push(("q", self.keywords.join(" ")));
+        }
+        let filters = [
+            ("artist", &self.artist),
+            ("title", &self.title),
+            ("genre", &self.genre),
+            ("language", &self.language),
+        ];
+        for (name, value) in filters.iter() {
+            if let Some(value) = value {
+                parameters.push((*name, value.clone()));
+            }
+        }
+        if let Some(duet) = self.duet {
+            parameters.push(("duet", duet.to_string()));
+        }
+        if let Some(page) = self.page {
+            parameters.push(("page", page.to_string()));
+        }
+        if let Some(limit) = self.limit {
+            parameters.push(("limit", limit.to_string()));
+        }
+        if let Some(sort) = self.sort {
+            parameters.push(("sort", sort.as_str().to_string()));
+            parameters.push(("order", if self.descending { "desc" } else { "asc" }.to_string()));
+        }
+        parameters
+    }
+}
+
+/// Split the input at whitespace outside of double quotes, removing the quotes.
+fn split_terms(input: &str) -> Vec<String> {
+    let mut terms = Vec::new();
+    let mut term = String::new();
+    let mut quoted = false;
+    for c in input.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => {
+                if !term.is_empty() {
+                    terms.push(std::mem::take(&mut term));
+                }
+            }
+            c => term.push(c),
+        }
+    }
+    if !term.is_empty() {
+        terms.push(term);
+    }
+    terms
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_keywords_and_filters() {
+        let query = Query::parse(r#"waterloo artist:ABBA title:"dancing queen" duet:no"#).unwrap();
+        assert_eq!(query.keywords, vec!["waterloo"]);
+        assert_eq!(query.artist.as_deref(), Some("ABBA"));
+        assert_eq!(query.title.as_deref(), Some("dancing queen"));
+        assert_eq!(query.duet, Some(false));
+    }
+
+    #[test]
+    fn keeps_unknown_filters_as_keywords() {
+        let query = Query::parse("re:zero opening: rock&roll #1").unwrap();
+        assert_eq!(query.keywords, vec!["re:zero", "opening:", "rock&roll", "#1"]);
+        assert!(Query::parse("duet:maybe").is_err());
+    }
+
+    #[test]
+    fn maps_to_parameters() {
+        let query = Query {
+            page: Some(2),
+            limit: Some(20),
+            sort: Some(SortField::Title),
+            descending: true,
+            ..Query::parse("rock&roll genre:pop").unwrap()
+        };
+        assert_eq!(
+            query.parameters(),
+            vec![
+                ("q", "rock&roll".to_string()),
+                ("genre", "pop".to_string()),
+                ("page", "2".to_string()),
+                ("limit", "20".to_string()),
+                ("sort", "title".to_string()),
+                ("order", "desc".to_string()),
+            ]
+        );
+    }
+}
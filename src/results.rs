@@ -0,0 +1,252 @@
+//! The results screen shown after a song has been sung.
+
+use colored::Color;
+
+use crate::draw::line_to_str;
+use crate::frame::{Frame, Style};
+use crate::score::{Score, Scorer};
+
+/// Height of the per-line bar chart on the results screen.
+const CHART_HEIGHT: u16 = 10;
+/// Width of the bars in the plain text output.
+const TEXT_BAR_WIDTH: usize = 20;
+
+/// Ratings by the minimal total score needed to reach them.
+const RATINGS: [(f32, &str); 8] = [
+    (9_000.0, "Superstar"),
+    (8_000.0, "Hit Artist"),
+    (7_000.0, "Lead Singer"),
+    (6_000.0, "Rising Star"),
+    (5_000.0, "Hopeful"),
+    (4_000.0, "Wannabe"),
+    (2_000.0, "Amateur"),
+    (0.0, "Tone Deaf"),
+];
+
+/// The outcome of a sung song.
+pub struct Results {
+    score: Score,
+    /// The text of every line and how accurate it was sung.
+    lines: Vec<(String, Option<f32>)>,
+}
+
+impl Results {
+    /// Collect the results of the given scorer for the lines it scored.
+    pub fn new(scorer: &Scorer, lines: &[ultrastar_txt::Line]) -> Results {
+        Results {
+            score: scorer.score(),
+            lines: lines
+                .iter()
+                .map(line_to_str)
+                .zip(scorer.line_accuracies())
+                .collect(),
+        }
+    }
+
+    /// The rating label for the total score.
+    pub fn rating(&self) -> &'static str {
+        let total = self.score.total();
+        RATINGS
+            .iter()
+            .find(|(min_score, _)| total >= *min_score)
+            .map(|(_, rating)| *rating)
+            .unwrap_or("Tone Deaf")
+    }
+
+    /// Draw the results into the frame, headed by `title`.
+    pub fn draw_screen(&self, frame: &mut Frame, title: &str) {
+        frame.put_str(3, 1, title, Style::default().bold());
+        let summary = [
+            ("Score", format!("{:5.0}", self.score.total()), Style::default().bold()),
+            ("Notes", format!("{:5.0}", self.score.notes), Style::default()),
+            ("Golden notes", format!("{:5.0}", self.score.golden), Style::fg(Color::Yellow)),
+            ("Line bonus", format!("{:5.0}", self.score.line_bonus), Style::default()),
+            ("Rating", self.rating().to_string(), Style::default().bold()),
+        ];
+        for (row, (label, value, style)) in summary.iter().enumerate() {
+            let row = 3 + row as u16;
+            let col = frame.put_str(3, row, &format!("{:>12}: ", label), Style::default());
+            frame.put_str(col, row, value, *style);
+        }
+
+        // one column per line, as wide as the frame allows
+        let chart_top = 3 + summary.len() as u16 + 2;
+        let column_width = (frame.width().saturating_sub(4) / self.lines.len().max(1) as u16).clamp(1, 3);
+        let bar = "#".repeat(column_width.saturating_sub(1).max(1) as usize);
+        for (column, (_, accuracy)) in self.lines.iter().enumerate() {
+            let accuracy = match accuracy {
+                Some(accuracy) => *accuracy,
+                None => continue,
+            };
+            let hpos = 3 + column as u16 * column_width;
+            if hpos + column_width > frame.width() {
+                break;
+            }
+            let bar_height = (accuracy * CHART_HEIGHT as f32).round() as u16;
+            for row in 0..bar_height {
+                frame.put_str(hpos, chart_top + CHART_HEIGHT - row, &bar, accuracy_style(accuracy));
+            }
+        }
+
+        frame.put_str(
+            3,
+            chart_top + CHART_HEIGHT + 2,
+            "Press any key to continue",
+            Style::fg(Color::BrightBlack),
+        );
+    }
+
+    /// Render the results as plain text, e.g. for printing them to stdout.
+    pub fn to_plain_text(&self) -> String {
+        let mut output = String::new();
+        output.push_str(&format!("Score:        {:5.0}\n", self.score.total()));
+        output.push_str(&format!("Notes:        {:5.0}\n", self.score.notes));
+        output.push_str(&format!("Golden notes: {:5.0}\n", self.score.golden));
+        output.push_str(&format!("Line bonus:   {:5.0}\n", self.score.line_bonus));
+        output.push_str(&format!("Rating:       {}\n\n", self.rating()));
+
+        for (number, (text, accuracy)) in self.lines.iter().enumerate() {
+            let accuracy = match accuracy {
+                Some(accuracy) => *accuracy,
+                None => continue,
+            };
+            let filled = (accuracy * TEXT_BAR_WIDTH as f32).round() as usize;
+            output.push_str(&format!(
+                "{:3} [{}{}] {:3.0}% {}\n",
+                number + 1,
+                "#".repeat(filled),
+                " ".repeat(TEXT_BAR_WIDTH - filled),
+                accuracy * 100.0,
+                text.trim()
+            ));
+        }
+
+        output
+    }
+}
+
+/// The color of a bar, by how accurate the line was sung.
+fn accuracy_style(accuracy: f32) -> Style {
+    if accuracy >= 0.8 {
+        Style::fg(Color::BrightGreen)
+    } else if accuracy >= 0.5 {
+        Style::fg(Color::Yellow)
+    } else {
+        Style::fg(Color::Red)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Instant;
+
+    use pitch_calc::Step;
+    use ultrastar_txt::{Line, Note};
+
+    use crate::pitch::PitchReading;
+    use crate::score::Difficulty;
+
+    fn line(start: i32, texts: &[&str]) -> Line {
+        let notes = texts
+            .iter()
+            .enumerate()
+            .map(|(index, text)| Note::Regular {
+                start: start + index as i32 * 2,
+                duration: 2,
+                pitch: 0,
+                text: text.to_string(),
+            })
+            .collect();
+        Line { start, rel: None, notes }
+    }
+
+    fn results_with_total(total: f32) -> Results {
+        Results {
+            score: Score {
+                notes: total,
+                golden: 0.0,
+                line_bonus: 0.0,
+            },
+            lines: Vec::new(),
+        }
+    }
+
+    /// Sing the first line of two in tune and only the first note of the second.
+    fn sung_results() -> Results {
+        let lines = [line(0, &["Hel", "lo "]), line(10, &["Wor", "ld"])];
+        let reading = PitchReading {
+            frequency: 261.6,
+            step: Step(60.0),
+            cents: 0.0,
+            clarity: 1.0,
+            rms: 1.0,
+            timestamp: Instant::now(),
+        };
+        let mut scorer = Scorer::new(&lines, Difficulty::Medium);
+        for beat in 0..14 {
+            let sung = beat < 4 || (10..12).contains(&beat);
+            scorer.update(beat as f32, if sung { Some(&reading) } else { None });
+        }
+        scorer.finish();
+        Results::new(&scorer, &lines)
+    }
+
+    #[test]
+    fn rates_by_the_total_score() {
+        assert_eq!(results_with_total(0.0).rating(), "Tone Deaf");
+        assert_eq!(results_with_total(1_999.0).rating(), "Tone Deaf");
+        assert_eq!(results_with_total(2_000.0).rating(), "Amateur");
+        assert_eq!(results_with_total(5_500.0).rating(), "Hopeful");
+        assert_eq!(results_with_total(8_999.9).rating(), "Hit Artist");
+        assert_eq!(results_with_total(9_000.0).rating(), "Superstar");
+        assert_eq!(results_with_total(10_000.0).rating(), "Superstar");
+    }
+
+    #[test]
+    fn keeps_the_text_and_accuracy_of_every_line() {
+        let results = sung_results();
+        assert_eq!(
+            results.lines,
+            vec![("Hello ".to_string(), Some(1.0)), ("World".to_string(), Some(0.5))]
+        );
+        assert!(results.score.line_bonus > 0.0);
+    }
+
+    #[test]
+    fn lists_the_lines_as_plain_text() {
+        let results = sung_results();
+        let score = results.score;
+        let expected = format!(
+            "Score:        {:5.0}\nNotes:        {:5.0}\nGolden notes:     0\nLine bonus:   {:5.0}\nRating:       {}\n\n  \
+             1 [####################] 100% Hello\n  2 [##########          ]  50% World\n",
+            score.total(),
+            score.notes,
+            score.line_bonus,
+            results.rating()
+        );
+        assert_eq!(results.to_plain_text(), expected);
+    }
+
+    #[test]
+    fn draws_the_screen_into_a_frame() {
+        let results = sung_results();
+        let mut frame = Frame::new(40, 24);
+        results.draw_screen(&mut frame, "P1");
+        let text = frame.to_plain_text();
+        let rows: Vec<&str> = text.lines().collect();
+
+        assert_eq!(rows[0], "  P1");
+        assert_eq!(rows[2], format!("         Score: {:5.0}", results.score.total()));
+        assert_eq!(rows[6], format!("        Rating: {}", results.rating()));
+        // a full bar for the first line, half of one for the second
+        assert_eq!(rows[10], "  ##");
+        assert_eq!(rows[14], "  ##");
+        assert_eq!(rows[15], "  ## ##");
+        assert_eq!(rows[19], "  ## ##");
+        assert_eq!(rows[21], "  Press any key to continue");
+        assert_eq!(frame.cell(3, 11).unwrap().style, Style::fg(Color::BrightGreen));
+        assert_eq!(frame.cell(6, 20).unwrap().style, Style::fg(Color::Yellow));
+    }
+}
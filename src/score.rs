@@ -0,0 +1,507 @@
+//! Scores the sung pitches against the notes of a song.
+//!
+//! The scoring follows the classic Ultrastar scale: a perfectly sung song is
+//! worth 10,000 points, 1,000 of which are awarded as line bonus.  Every beat
+//! of a regular note that was hit earns points, golden notes are worth double
+//! and freestyle notes are not scored at all.
+
+use std::str::FromStr;
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::pitch::PitchReading;
+
+/// The score of a perfectly sung song.
+pub const MAX_SCORE: f32 = 10_000.0;
+/// The part of `MAX_SCORE` that is awarded for sung lines.
+pub const MAX_LINE_BONUS: f32 = 1_000.0;
+
+/// How exact the singer has to hit a note.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Difficulty {
+    Easy,
+    Medium,
+    Hard,
+}
+
+impl Difficulty {
+    /// The allowed distance between the sung and the expected pitch in cents.
+    fn tolerance(self) -> f32 {
+        match self {
+            Difficulty::Easy => 250.0,
+            Difficulty::Medium => 150.0,
+            Difficulty::Hard => 50.0,
+        }
+    }
+}
+
+impl FromStr for Difficulty {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Difficulty, String> {
+        match s {
+            "easy" => Ok(Difficulty::Easy),
+            "medium" => Ok(Difficulty::Medium),
+            "hard" => Ok(Difficulty::Hard),
+            _ => Err(format!("unknown difficulty `{}`", s)),
+        }
+    }
+}
+
+/// The points reached so far, split by where they came from.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Score {
+    /// Points for hit beats of regular notes.
+    pub notes: f32,
+    /// Points for hit beats of golden notes.
+    pub golden: f32,
+    /// Points awarded for finished lines.
+    pub line_bonus: f32,
+}
+
+impl Score {
+    /// The sum of all points.
+    pub fn total(&self) -> f32 {
+        self.notes + self.golden + self.line_bonus
+    }
+}
+
+/// How many beats of a note were hit.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NoteHits {
+    /// Index of the line the note belongs to.
+    pub line: usize,
+    pub start: i32,
+    pub golden: bool,
+    /// The number of hit beats.
+    pub hit: usize,
+    /// The number of beats of the note.
+    pub beats: usize,
+}
+
+/// A scorable note and the beats of it that were hit.
+struct ScoredNote {
+    start: i32,
+    pitch: i32,
+    golden: bool,
+    /// Index of the line this note belongs to.
+    line: usize,
+    /// One entry per beat of the note.
+    hits: Vec<bool>,
+}
+
+impl ScoredNote {
+    fn weight(&self) -> f32 {
+        if self.golden {
+            2.0
+        } else {
+            1.0
+        }
+    }
+
+    fn end(&self) -> i32 {
+        self.start + self.hits.len() as i32
+    }
+}
+
+/// Bookkeeping for the line bonus.
+struct ScoredLine {
+    /// The beat after the last scorable note of the line.
+    end: i32,
+    /// The maximal weighted beats that can be hit in this line.
+    weight: f32,
+    /// Whether the line has been sung completely.
+    finished: bool,
+}
+
+/// Accumulates the score of one singer over the course of a song.
+pub struct Scorer {
+    /// All scorable notes, ordered by their start.
+    notes: Vec<ScoredNote>,
+    lines: Vec<ScoredLine>,
+    /// The indexes of the lines, ordered by their end.
+    lines_by_end: Vec<usize>,
+    /// The number of lines in `lines_by_end` that are finished.
+    finished_lines: usize,
+    tolerance: f32,
+    points_per_beat: f32,
+    line_bonus_per_line: f32,
+    /// The last beat that was scored.
+    last_beat: Option<i32>,
+}
+
+impl Scorer {
+    /// Create a new scorer for the given lines of a song.
+    pub fn new(lines: &[ultrastar_txt::Line], difficulty: Difficulty) -> Scorer {
+        let mut notes = Vec::new();
+        let mut scored_lines = Vec::new();
+
+        for (line_index, line) in lines.iter().enumerate() {
+            let mut scored_line = ScoredLine {
+                end: line.start,
+                weight: 0.0,
+                finished: false,
+            };
+            for note in line.notes.iter() {
+                let (start, duration, pitch, golden) = match *note {
+                    ultrastar_txt::Note::Regular {
+                        start,
+                        duration,
+                        pitch,
+                        text: _,
+                    } => (start, duration, pitch, false),
+                    ultrastar_txt::Note::Golden {
+                        start,
+                        duration,
+                        pitch,
+                        text: _,
+                    } => (start, duration, pitch, true),
+                    // freestyle notes can't be hit or missed
+                    _ => continue,
+                };
+                let note = ScoredNote {
+                    start,
+                    pitch,
+                    golden,
+                    line: line_index,
+                    hits: vec![false; duration.max(0) as usize],
+                };
+                scored_line.end = scored_line.end.max(note.end());
+                scored_line.weight += note.weight() * note.hits.len() as f32;
+                notes.push(note);
+            }
+            scored_lines.push(scored_line);
+        }
+        notes.sort_by_key(|note| note.start);
+        let mut lines_by_end: Vec<usize> = (0..scored_lines.len()).collect();
+        lines_by_end.sort_by_key(|index| scored_lines[*index].end);
+
+        let total_weight: f32 = scored_lines.iter().map(|line| line.weight).sum();
+        let scorable_lines = scored_lines.iter().filter(|line| line.weight > 0.0).count();
+
+        Scorer {
+            notes,
+            lines: scored_lines,
+            lines_by_end,
+            finished_lines: 0,
+            tolerance: difficulty.tolerance(),
+            points_per_beat: if total_weight > 0.0 {
+                (MAX_SCORE - MAX_LINE_BONUS) / total_weight
+            } else {
+                0.0
+            },
+            line_bonus_per_line: if scorable_lines > 0 {
+                MAX_LINE_BONUS / scorable_lines as f32
+            } else {
+                0.0
+            },
+            last_beat: None,
+        }
+    }
+
+    /// Score all beats up to `beat` with the currently sung note.
+    ///
+    /// Beats that passed since the last update are all judged by `sung`.
+    pub fn update(&mut self, beat: f32, sung: Option<&PitchReading>) {
+        let current_beat = beat.floor() as i32;
+        let first_beat = match self.last_beat {
+            Some(last_beat) if last_beat < current_beat => last_beat + 1,
+            Some(_) => return,
+            None => current_beat,
+        };
+        self.last_beat = Some(current_beat);
+
+        let sung_pitch = sung.map(|reading| reading.exact_step());
+        if let Some(sung_pitch) = sung_pitch {
+            for beat in first_beat..=current_beat {
+                self.judge_beat(beat, sung_pitch);
+            }
+        }
+        while let Some(&index) = self.lines_by_end.get(self.finished_lines) {
+            if self.lines[index].end > current_beat {
+                break;
+            }
+            self.lines[index].finished = true;
+            self.finished_lines += 1;
+        }
+    }
+
+    /// Continue scoring at `beat`, e.g. after seeking.
+    ///
+    /// The beats in between are not judged.
+    pub fn seek(&mut self, beat: f32) {
+        self.last_beat = Some(beat.floor() as i32);
+    }
+
+    /// Mark all lines as finished, e.g. because the song has ended.
+    pub fn finish(&mut self) {
+        for line in self.lines.iter_mut() {
+            line.finished = true;
+        }
+        self.finished_lines = self.lines.len();
+    }
+
+    /// Calculate the score reached so far.
+    pub fn score(&self) -> Score {
+        let mut score = Score::default();
+        for note in self.notes.iter() {
+            let hits = note.hits.iter().filter(|hit| **hit).count() as f32;
+            let points = hits * note.weight() * self.points_per_beat;
+            if note.golden {
+                score.golden += points;
+            } else {
+                score.notes += points;
+            }
+        }
+        score.line_bonus = self
+            .line_accuracies()
+            .iter()
+            .zip(self.lines.iter())
+            .filter(|(_, line)| line.finished)
+            .map(|(accuracy, _)| accuracy.unwrap_or(0.0) * self.line_bonus_per_line)
+            .sum();
+        score
+    }
+
+    /// The ratio of hit to scorable weighted beats for every line.
+    ///
+    /// Lines without scorable notes have no accuracy.
+    pub fn line_accuracies(&self) -> Vec<Option<f32>> {
+        let mut hit_weights = vec![0.0; self.lines.len()];
+        for note in self.notes.iter() {
+            let hits = note.hits.iter().filter(|hit| **hit).count() as f32;
+            hit_weights[note.line] += hits * note.weight();
+        }
+        hit_weights
+            .into_iter()
+            .zip(self.lines.iter())
+            .map(|(hit_weight, line)| if line.weight > 0.0 {
+                Some(hit_weight / line.weight)
+            } else {
+                None
+            })
+            .collect()
+    }
+
+    /// The hit beats of every scorable note, ordered by their start.
+    pub fn note_hits(&self) -> Vec<NoteHits> {
+        self.notes
+            .iter()
+            .map(|note| NoteHits {
+                line: note.line,
+                start: note.start,
+                golden: note.golden,
+                hit: note.hits.iter().filter(|hit| **hit).count(),
+                beats: note.hits.len(),
+            })
+            .collect()
+    }
+
+    /// Mark `beat` as hit if `sung_pitch` matches the note at that beat.
+    fn judge_beat(&mut self, beat: i32, sung_pitch: f32) {
+        let index = self.notes.partition_point(|note| note.start <= beat);
+        if index == 0 {
+            return;
+        }
+        let tolerance = self.tolerance;
+        let note = &mut self.notes[index - 1];
+        if beat < note.end() && cents_distance(note.pitch as f32, sung_pitch) <= tolerance {
+            note.hits[(beat - note.start) as usize] = true;
+        }
+    }
+}
+
+/// The distance between two pitches in cents, ignoring the octave.
+fn cents_distance(a: f32, b: f32) -> f32 {
+    let distance = (a - b).rem_euclid(12.0);
+    distance.min(12.0 - distance) * 100.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Instant;
+
+    use pitch_calc::Step;
+    use ultrastar_txt::{Line, Note};
+
+    fn regular(start: i32, duration: i32, pitch: i32) -> Note {
+        Note::Regular {
+            start,
+            duration,
+            pitch,
+            text: "la".to_string(),
+        }
+    }
+
+    fn golden(start: i32, duration: i32, pitch: i32) -> Note {
+        Note::Golden {
+            start,
+            duration,
+            pitch,
+            text: "la".to_string(),
+        }
+    }
+
+    fn freestyle(start: i32, duration: i32, pitch: i32) -> Note {
+        Note::Freestyle {
+            start,
+            duration,
+            pitch,
+            text: "la".to_string(),
+        }
+    }
+
+    fn line(start: i32, notes: Vec<Note>) -> Line {
+        Line { start, rel: None, notes }
+    }
+
+    /// A reading of the given step, as the pitch detection would report it.
+    fn sung(step: f32) -> PitchReading {
+        let nearest = step.round();
+        PitchReading {
+            frequency: 0.0,
+            step: Step(nearest),
+            cents: (step - nearest) * 100.0,
+            clarity: 1.0,
+            rms: 1.0,
+            timestamp: Instant::now(),
+        }
+    }
+
+    /// Sing `step` from `from` up to and including `to`, beat by beat.
+    fn sing(scorer: &mut Scorer, from: i32, to: i32, step: Option<f32>) {
+        let reading = step.map(sung);
+        for beat in from..=to {
+            scorer.update(beat as f32, reading.as_ref());
+        }
+    }
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!((actual - expected).abs() < 1e-2, "{} instead of {}", actual, expected);
+    }
+
+    /// The hit beats of a single note of pitch 0 sung `offset` semitones off.
+    fn hits_with(difficulty: Difficulty, offset: f32) -> usize {
+        let mut scorer = Scorer::new(&[line(0, vec![regular(0, 4, 0)])], difficulty);
+        sing(&mut scorer, 0, 3, Some(offset));
+        scorer.note_hits()[0].hit
+    }
+
+    #[test]
+    fn matches_notes_in_any_octave() {
+        let lines = [line(0, vec![regular(0, 2, 5), regular(2, 2, 5), regular(4, 2, 5)])];
+        let mut scorer = Scorer::new(&lines, Difficulty::Hard);
+        sing(&mut scorer, 0, 1, Some(5.0 + 12.0));
+        sing(&mut scorer, 2, 3, Some(5.0 - 24.0));
+        sing(&mut scorer, 4, 5, Some(6.0 + 12.0));
+        let hits: Vec<usize> = scorer.note_hits().iter().map(|note| note.hit).collect();
+        assert_eq!(hits, vec![2, 2, 0]);
+    }
+
+    #[test]
+    fn wraps_the_distance_around_the_octave() {
+        assert_close(cents_distance(0.0, 11.5), 50.0);
+        assert_close(cents_distance(11.0, -1.0), 0.0);
+        assert_close(cents_distance(0.0, 6.0), 600.0);
+    }
+
+    #[test]
+    fn difficulties_have_their_own_tolerance() {
+        assert_eq!(hits_with(Difficulty::Hard, 0.4), 4);
+        assert_eq!(hits_with(Difficulty::Hard, 1.0), 0);
+        assert_eq!(hits_with(Difficulty::Medium, 1.0), 4);
+        assert_eq!(hits_with(Difficulty::Medium, -1.4), 4);
+        assert_eq!(hits_with(Difficulty::Medium, 2.0), 0);
+        assert_eq!(hits_with(Difficulty::Easy, 2.0), 4);
+        assert_eq!(hits_with(Difficulty::Easy, -2.5), 4);
+        assert_eq!(hits_with(Difficulty::Easy, 3.0), 0);
+    }
+
+    #[test]
+    fn golden_notes_weigh_double() {
+        let lines = [line(0, vec![regular(0, 4, 0), golden(4, 4, 0)])];
+
+        let mut scorer = Scorer::new(&lines, Difficulty::Medium);
+        sing(&mut scorer, 0, 3, Some(0.0));
+        sing(&mut scorer, 4, 7, None);
+        let regular_only = scorer.score();
+
+        let mut scorer = Scorer::new(&lines, Difficulty::Medium);
+        sing(&mut scorer, 0, 3, None);
+        sing(&mut scorer, 4, 7, Some(0.0));
+        let golden_only = scorer.score();
+
+        // 12 weighted beats share the 9,000 note points
+        assert_close(regular_only.notes, 3_000.0);
+        assert_close(regular_only.golden, 0.0);
+        assert_close(golden_only.notes, 0.0);
+        assert_close(golden_only.golden, 6_000.0);
+    }
+
+    #[test]
+    fn freestyle_notes_are_not_scored() {
+        let lines = [
+            line(0, vec![freestyle(0, 4, 0)]),
+            line(8, vec![regular(8, 4, 0), freestyle(12, 4, 0)]),
+        ];
+        let mut scorer = Scorer::new(&lines, Difficulty::Medium);
+        assert_eq!(scorer.note_hits().len(), 1);
+
+        sing(&mut scorer, 0, 16, Some(0.0));
+        scorer.finish();
+        assert_eq!(scorer.line_accuracies(), vec![None, Some(1.0)]);
+        // the line without scorable notes takes no share of the bonus
+        assert_close(scorer.score().line_bonus, MAX_LINE_BONUS);
+        assert_close(scorer.score().total(), MAX_SCORE);
+    }
+
+    #[test]
+    fn awards_the_line_bonus_for_finished_lines() {
+        let lines = [line(0, vec![regular(0, 4, 0)]), line(8, vec![regular(8, 4, 0)])];
+        let mut scorer = Scorer::new(&lines, Difficulty::Medium);
+
+        sing(&mut scorer, 0, 3, Some(0.0));
+        // the last beat of the line is still being sung
+        assert_close(scorer.score().line_bonus, 0.0);
+        sing(&mut scorer, 4, 4, None);
+        assert_close(scorer.score().line_bonus, 500.0);
+
+        // half of the second line, finished by the end of the song
+        sing(&mut scorer, 5, 9, Some(0.0));
+        sing(&mut scorer, 10, 10, None);
+        assert_close(scorer.score().line_bonus, 500.0);
+        scorer.finish();
+        assert_close(scorer.score().line_bonus, 750.0);
+        assert_eq!(scorer.line_accuracies(), vec![Some(1.0), Some(0.5)]);
+    }
+
+    #[test]
+    fn a_perfect_run_is_worth_the_maximal_score() {
+        let lines = [
+            line(0, vec![regular(0, 3, 2), golden(4, 2, 4), freestyle(7, 1, 0)]),
+            line(10, vec![regular(10, 5, 7), regular(16, 1, -3)]),
+            line(20, vec![golden(20, 7, 12), regular(28, 2, 11)]),
+        ];
+        let pitches = |beat: i32| match beat {
+            0..=3 => 2.0,
+            4..=9 => 4.0,
+            10..=15 => 7.0,
+            16..=19 => -3.0,
+            20..=27 => 12.0,
+            _ => 11.0,
+        };
+        let mut scorer = Scorer::new(&lines, Difficulty::Hard);
+        for beat in 0..32 {
+            scorer.update(beat as f32, Some(&sung(pitches(beat))));
+        }
+        scorer.finish();
+
+        let score = scorer.score();
+        assert_close(score.line_bonus, MAX_LINE_BONUS);
+        assert_close(score.total(), MAX_SCORE);
+        assert_eq!(score.total().round(), 10_000.0);
+    }
+}
@@ -1,7 +1,11 @@
+//! Searches song servers and finds the files of their songs.
+
 use crate::errors::*;
-use tempfile::NamedTempFile;
-use std::io::copy;
+use crate::query::Query;
+use std::{collections::HashSet, thread, time::Duration};
 
+use log::warn;
+use reqwest::{blocking::Client, StatusCode};
 use serde_derive::Deserialize;
 
 // GET http://server.com/search/123 -> String(JSON) -> Vec<Struct>
@@ -12,57 +16,175 @@ use serde_derive::Deserialize;
 
 type Url = String;
 
-const SERVER_URL: &str = "http://localhost:8000";
+/// The version of the server API this client speaks.
+const API_VERSION: u32 = 1;
+/// How long to wait for a server to answer.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What a server tells about itself at `/version`.
+#[derive(Deserialize)]
+struct VersionResponse {
+    api_version: u32,
+}
 
 #[derive(Deserialize)]
 struct ServerResponse {
     pub results: Vec<SearchResult>,
 }
 
-#[derive(Deserialize)]
-struct SearchResult {
-    artist: String,
-    title: String,
-    genre: Option<String>,
-    path: String,
+/// A song found on the server.
+#[derive(Clone, Debug, Deserialize)]
+pub struct SearchResult {
+    pub artist: String,
+    pub title: String,
+    pub genre: Option<String>,
+    /// The path of the song file on the server.
+    pub path: String,
+    /// The Url of the server the song was found on.
+    #[serde(skip)]
+    pub server: Url,
+}
+
+impl SearchResult {
+    /// The artist and title, the same for a song found on different servers.
+    fn song_key(&self) -> (String, String) {
+        (self.artist.trim().to_lowercase(), self.title.trim().to_lowercase())
+    }
 }
 
-/// Search online for a given keyword and either print a list of songs found or pick one of them and return its Url
+/// Search all servers at once and return the songs found.
 ///
-/// pick: if `None`, the list with all fetched songs will be printed,
-///       if `Some(i)`, the Url of the `i`th song will be returned
-pub fn search(keyword: &str, pick: Option<usize>) -> Result<Option<Url>> {
-    // TODO: add keyword escaping to avoid injections
-    let response = reqwest::blocking::get(&format!("{}/search?q={}", SERVER_URL, keyword)).chain_err(|| "server unreachable")?;
-    let result: ServerResponse = response.json().chain_err(|| "failed deserializing server response")?;
-
-    if let Some(index) = pick {
-        // Ok(files.get(index).chain_err()
-        let path = &result.results.get(index).chain_err(|| "index out of bounds")?.path;
-        Ok(Some(format!("{}/{}", SERVER_URL, path)))
-    } else {
-        for (i, file) in result.results.iter().enumerate() {
-            if let Some(genre) = file.genre.as_ref() {
-                println!("{number:2}: {title} - {artist} ({genre})", number = i, title = file.title, artist = file.artist, genre = genre);
-            } else {
-                println!("{number:2}: {title} - {artist}", number = i, title = file.title, artist = file.artist);
+/// Songs found on several servers are listed once.  Servers that cannot be
+/// reached or speak another API version are skipped, it is only an error if
+/// none of them answers.
+pub fn search_songs(servers: &[Url], query: &Query) -> Result<Vec<SearchResult>> {
+    let client = Client::builder()
+        .timeout(TIMEOUT)
+        .build()
+        .chain_err(|| "could not create http client")?;
+
+    let answers: Vec<Result<Vec<SearchResult>>> = thread::scope(|scope| {
+        let searches: Vec<_> = servers
+            .iter()
+            .map(|server| scope.spawn(|| search_server(&client, server, query)))
+            .collect();
+        searches
+            .into_iter()
+            .map(|search| search.join().unwrap_or_else(|_| Err("search thread panicked".into())))
+            .collect()
+    });
+
+    let mut songs: Vec<SearchResult> = Vec::new();
+    let mut known_songs = HashSet::new();
+    let mut error = None;
+    let mut answered = false;
+    for answer in answers {
+        match answer {
+            Ok(results) => {
+                answered = true;
+                for song in results {
+                    if known_songs.insert(song.song_key()) {
+                        songs.push(song);
+                    }
+                }
+            }
+            Err(e) => {
+                warn!("{}", e);
+                error = Some(e);
             }
         }
+    }
+    match error {
+        Some(e) if !answered => Err(e),
+        _ if servers.is_empty() => Err("no song server configured".into()),
+        _ => Ok(songs),
+    }
+}
+
+/// Search a single server, after making sure it speaks our API version.
+fn search_server(client: &Client, server_url: &str, query: &Query) -> Result<Vec<SearchResult>> {
+    let server_url = server_url.trim_end_matches('/');
+    let version = api_version(client, server_url)?;
+    if version != API_VERSION {
+        return Err(format!(
+            "server {} speaks API version {}, but only version {} is supported",
+            server_url, version, API_VERSION
+        ).into());
+    }
+
+    let response = client
+        .get(format!("{}/search", server_url))
+        .query(&query.parameters())
+        .send()
+        .chain_err(|| format!("server {} unreachable", server_url))?;
+    let result: ServerResponse = response
+        .json()
+        .chain_err(|| format!("failed deserializing response of server {}", server_url))?;
+    Ok(result
+        .results
+        .into_iter()
+        .map(|song| SearchResult {
+            server: server_url.to_string(),
+            ..song
+        })
+        .collect())
+}
 
-        Ok(None)
+/// Ask the server which API version it speaks.
+///
+/// Servers without a `/version` endpoint predate it and speak version 1.
+fn api_version(client: &Client, server_url: &str) -> Result<u32> {
+    let response = client
+        .get(format!("{}/version", server_url))
+        .send()
+        .chain_err(|| format!("server {} unreachable", server_url))?;
+    if response.status() == StatusCode::NOT_FOUND {
+        return Ok(1);
     }
+    let version: VersionResponse = response
+        .error_for_status()
+        .and_then(|response| response.json())
+        .chain_err(|| format!("server {} sent an invalid version", server_url))?;
+    Ok(version.api_version)
+}
+
+/// The Url of the song file of a search result.
+pub fn song_url(song: &SearchResult) -> Url {
+    format!("{}/{}", song.server, song.path)
 }
 
-/// Try to download the given file
-pub fn download_file(url: String) -> Result<NamedTempFile> {
-    let mut dest = NamedTempFile::new()
-        .chain_err(|| "could not create temporary file")?;
+/// The Url of a file on the server, encoding every part of its `/` separated path.
+pub fn file_url(server_url: &str, path: &str) -> Result<reqwest::Url> {
+    let invalid = || format!("invalid server url {}", server_url);
+    let mut url = reqwest::Url::parse(server_url).chain_err(invalid)?;
+    url.path_segments_mut()
+        .map_err(|_| invalid())?
+        .pop_if_empty()
+        .extend(path.split('/').filter(|segment| !segment.is_empty()));
+    Ok(url)
+}
 
-    let mut response = reqwest::blocking::get(&url)
-        .chain_err(|| "could not retrieve .txt from server")?;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    copy(&mut response, dest.as_file_mut())
-        .chain_err(|| "could not write to .txt file")?;
+    #[test]
+    fn encodes_the_parts_of_file_paths() {
+        let url = file_url("http://localhost:8080", "ABBA - Waterloo/Waterloo #1.mp3").unwrap();
+        assert_eq!(url.as_str(), "http://localhost:8080/ABBA%20-%20Waterloo/Waterloo%20%231.mp3");
+        assert_eq!(url.path_segments().unwrap().count(), 2);
+    }
 
-    Ok(dest)
+    #[test]
+    fn appends_file_paths_to_the_path_of_the_server() {
+        let url = file_url("https://example.com/songs/", "a/b.txt").unwrap();
+        assert_eq!(url.as_str(), "https://example.com/songs/a/b.txt");
+        // empty parts are dropped, the server path stays
+        let url = file_url("https://example.com/songs", "/a//b.txt").unwrap();
+        assert_eq!(url.as_str(), "https://example.com/songs/a/b.txt");
+        // a question mark is part of the name, not a query
+        let url = file_url("https://example.com", "what?.txt").unwrap();
+        assert_eq!(url.query(), None);
+        assert!(file_url("not a url", "a.txt").is_err());
+    }
 }
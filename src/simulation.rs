@@ -0,0 +1,153 @@
+//! Scores a recorded vocal track instead of live microphones.
+//!
+//! The recording is cut into buffers like the ones captured from a
+//! microphone and fed through the same pitch detection and scoring, in
+//! lockstep with the beats of the song.  No audio is played or captured, so
+//! scoring can be checked against real recordings anywhere.
+
+use crate::errors::*;
+
+use ultrastar_txt::{Line, Note};
+
+use crate::audio_input::AudioInput;
+use crate::capture::{self, CaptureSettings};
+use crate::score::{Difficulty, Scorer};
+use crate::timing::Timing;
+
+/// Score a recording of the given lines, which starts with the song audio.
+///
+/// Every block of the input is analyzed with the thresholds of `settings`,
+/// mixing its channels, until the input ends.
+pub fn score_input(
+    lines: &[Line],
+    timing: &Timing,
+    input: &mut dyn AudioInput,
+    settings: &CaptureSettings,
+    difficulty: Difficulty,
+) -> Result<Scorer> {
+    let sample_rate = input.sample_rate();
+    capture::check_frames(&CaptureSettings {
+        sample_rate,
+        ..settings.clone()
+    })?;
+
+    let mut scorer = Scorer::new(lines, difficulty);
+    let mut frames = 0;
+    input.start()?;
+    while let Some(block) = input.next_block()? {
+        let samples = capture::mix_channels(&block.channels);
+        let reading = capture::detect_pitch(&samples, sample_rate, settings, block.timestamp);
+        // a block is analyzed once it is complete, like a captured one
+        frames += samples.len();
+        let ms = frames as f32 * 1000.0 / sample_rate as f32;
+        scorer.update(timing.beat_at(ms), reading.as_ref());
+    }
+    scorer.finish();
+    Ok(scorer)
+}
+
+/// List the hit beats of every scorable note.
+pub fn note_report(scorer: &Scorer, lines: &[Line]) -> String {
+    let mut output = String::new();
+    for hits in scorer.note_hits() {
+        let text = lines.get(hits.line).map(|line| note_text(line, hits.start)).unwrap_or("");
+        output.push_str(&format!(
+            "{:3} {:5} {:3}/{:<3} {}{}\n",
+            hits.line + 1,
+            hits.start,
+            hits.hit,
+            hits.beats,
+            if hits.golden { "*" } else { " " },
+            text.trim()
+        ));
+    }
+    output
+}
+
+/// The text of the scorable note of `line` starting at `start`.
+fn note_text(line: &Line, start: i32) -> &str {
+    line.notes
+        .iter()
+        .find_map(|note| match note {
+            Note::Regular { start: note_start, text, .. } | Note::Golden { start: note_start, text, .. }
+                if *note_start == start =>
+            {
+                Some(text.as_str())
+            }
+            _ => None,
+        })
+        .unwrap_or("")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio_input::{RecordedInput, ToneInput};
+
+    const SAMPLE_RATE: u32 = 44_100;
+
+    fn note(start: i32, duration: i32, pitch: i32) -> Note {
+        Note::Regular {
+            start,
+            duration,
+            pitch,
+            text: "la".to_string(),
+        }
+    }
+
+    /// A sine tone of `step` (0 is C4) for every `(start_ms, end_ms, step)`.
+    fn sing(parts: &[(f32, f32, f32)], length_ms: f32) -> Vec<f32> {
+        let mut samples = vec![0.0; (length_ms * SAMPLE_RATE as f32 / 1000.0) as usize];
+        for (start_ms, end_ms, step) in parts.iter() {
+            let frequency = 261.63 * 2f32.powf(step / 12.0);
+            let start = (start_ms * SAMPLE_RATE as f32 / 1000.0) as usize;
+            let end = (end_ms * SAMPLE_RATE as f32 / 1000.0) as usize;
+            for (i, sample) in samples[start..end].iter_mut().enumerate() {
+                *sample = (2.0 * std::f32::consts::PI * frequency * i as f32 / SAMPLE_RATE as f32).sin() * 0.5;
+            }
+        }
+        samples
+    }
+
+    #[test]
+    fn scores_a_recording_in_lockstep_with_the_song() {
+        // 60 BPM in quarters are 4 beats per second
+        let timing = Timing::new(60.0, 0.0, 4.0, &[]);
+        let lines = vec![Line {
+            start: 0,
+            rel: None,
+            notes: vec![note(4, 4, 0), note(12, 4, 7)],
+        }];
+        // the first note is sung right, the second one a tritone off
+        let samples = sing(&[(1000.0, 2000.0, 0.0), (3000.0, 4000.0, 1.0)], 5000.0);
+        let mut input = RecordedInput::new(vec![samples], SAMPLE_RATE, capture::FRAMES, false);
+
+        let scorer =
+            score_input(&lines, &timing, &mut input, &CaptureSettings::default(), Difficulty::Medium).unwrap();
+        let hits = scorer.note_hits();
+        assert!(hits[0].hit >= 3, "{:?}", hits[0]);
+        assert_eq!(hits[1].hit, 0);
+    }
+
+    #[test]
+    fn scores_a_generated_tone() {
+        let timing = Timing::new(60.0, 0.0, 4.0, &[]);
+        let lines = vec![Line {
+            start: 0,
+            rel: None,
+            notes: vec![note(4, 4, 9)],
+        }];
+        // a tone never ends, a recording of it does
+        let mut tone = ToneInput::new(440.0, 2, SAMPLE_RATE, 1024, false);
+        let blocks: Vec<_> = (0..100).map(|_| tone.next_block().unwrap().unwrap()).collect();
+        assert!(blocks.iter().all(|block| block.channels.len() == 2 && block.channels[0].len() == 1024));
+        let channels = (0..2)
+            .map(|channel| blocks.iter().flat_map(|block| block.channels[channel].clone()).collect())
+            .collect();
+        let mut input = RecordedInput::new(channels, SAMPLE_RATE, capture::FRAMES, false);
+
+        let scorer =
+            score_input(&lines, &timing, &mut input, &CaptureSettings::default(), Difficulty::Medium).unwrap();
+        assert_eq!(scorer.note_hits()[0].hit, 4);
+    }
+}
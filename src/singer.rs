@@ -0,0 +1,70 @@
+//! The singers taking part in a song.
+
+use crate::errors::*;
+
+use std::time::Duration;
+
+use colored::Color;
+
+use crate::capture::SharedReading;
+use crate::pitch::PitchReading;
+use crate::score::{Difficulty, Scorer};
+
+/// Readings older than this are ignored, e.g. if capturing stalls.
+const MAX_READING_AGE: Duration = Duration::from_millis(200);
+
+/// A singer with their microphone and score.
+pub struct Singer {
+    /// A short name to show next to the score.
+    pub name: String,
+    /// The color of the singer's pitch marker and score.
+    pub color: Color,
+    /// The index of the track (the part of a duet) the singer sings.
+    pub track: usize,
+    pub scorer: Scorer,
+    /// The reading written by the capture thread.
+    shared_reading: SharedReading,
+    /// The reading of the last update.
+    reading: Option<PitchReading>,
+}
+
+impl Singer {
+    /// Create the `index`th singer, singing the lines of the given track.
+    pub fn new(
+        index: usize,
+        shared_reading: SharedReading,
+        track: usize,
+        lines: &[ultrastar_txt::Line],
+        difficulty: Difficulty,
+        color: Color,
+    ) -> Singer {
+        Singer {
+            name: format!("P{}", index + 1),
+            color,
+            track,
+            scorer: Scorer::new(lines, difficulty),
+            shared_reading,
+            reading: None,
+        }
+    }
+
+    /// Fetch the current reading from the microphone and score it.
+    ///
+    /// Fails if the microphone stopped capturing.
+    pub fn update(&mut self, beat: f32) -> Result<()> {
+        let state = self.shared_reading.lock().unwrap().clone();
+        if let Some(error) = state.error {
+            return Err(format!("the microphone of {} stopped: {}", self.name, error).into());
+        }
+        self.reading = state
+            .reading
+            .filter(|reading| reading.timestamp.elapsed() < MAX_READING_AGE);
+        self.scorer.update(beat, self.reading.as_ref());
+        Ok(())
+    }
+
+    /// The reading of the last update.
+    pub fn reading(&self) -> Option<&PitchReading> {
+        self.reading.as_ref()
+    }
+}
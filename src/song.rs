@@ -0,0 +1,266 @@
+//! Prepares the lines of a song for singing.
+
+use crate::errors::*;
+
+use std::{fs, path::Path};
+
+use ultrastar_txt::{Header, Line, Note};
+
+use crate::timing::{self, Timing};
+
+/// A parsed song file.
+pub struct Song {
+    pub header: Header,
+    /// The lines of the song in absolute beats.
+    pub lines: Vec<Line>,
+    pub timing: Timing,
+}
+
+/// Load and parse a song file.
+pub fn load(path: &Path) -> Result<Song> {
+    let bytes = fs::read(path).chain_err(|| format!("could not read {}", path.display()))?;
+    // older song files are usually Latin-1
+    let txt = match String::from_utf8(bytes) {
+        Ok(txt) => txt,
+        Err(e) => e.into_bytes().into_iter().map(char::from).collect(),
+    };
+    let txt = txt.trim_start_matches('\u{feff}');
+
+    let header = ultrastar_txt::parse_txt_header_str(txt).chain_err(|| "could not parse song header")?;
+    let relative = header.relative.unwrap_or(false);
+    // the parser does not know BPM changes
+    let (txt, bpm_changes) = timing::split_bpm_changes(txt, relative);
+    let mut lines = ultrastar_txt::parse_txt_lines_str(&txt).chain_err(|| "could not parse song lines")?;
+    if relative {
+        timing::make_absolute(&mut lines);
+    }
+
+    let timing = Timing::from_header(&header, &bpm_changes);
+    Ok(Song { header, lines, timing })
+}
+
+/// Split the lines of a song into one track per part.
+///
+/// Songs without player change markers have a single track.  Duets are split
+/// at their `P1` and `P2` markers into two tracks, lines marked for both
+/// players (`P3`) are added to both of them.
+pub fn split_tracks(lines: &[Line]) -> Vec<Vec<Line>> {
+    if !is_duet(lines) {
+        return vec![lines.to_vec()];
+    }
+
+    let mut tracks: Vec<Vec<Line>> = vec![Vec::new(), Vec::new()];
+    let mut parts: &[usize] = &[0];
+    for line in lines.iter() {
+        let mut chunk = empty_line(line, Some(line.start));
+        for note in line.notes.iter() {
+            if let Note::PlayerChange { player } = *note {
+                // the line continues after the marker, but for another part,
+                // unless the marker comes before the first note of the line
+                let start = if chunk.0.notes.is_empty() { chunk.1 } else { None };
+                push_chunk(&mut tracks, parts, chunk);
+                parts = match player {
+                    1 => &[0],
+                    2 => &[1],
+                    _ => &[0, 1],
+                };
+                chunk = empty_line(line, start);
+            } else {
+                chunk.0.notes.push(note.clone());
+            }
+        }
+        push_chunk(&mut tracks, parts, chunk);
+    }
+
+    for track in tracks.iter_mut() {
+        track.sort_by_key(|line| first_note_start(line).unwrap_or(line.start));
+    }
+    tracks
+}
+
+/// The value of a header tag that the parser does not know, e.g. `PREVIEWSTART`.
+pub fn unknown_tag<'a>(header: &'a ultrastar_txt::Header, tag: &str) -> Option<&'a str> {
+    header
+        .unknown
+        .as_ref()?
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(tag))
+        .map(|(_, value)| value.trim())
+}
+
+/// The number in a header tag that the parser does not know.
+///
+/// Some songs use a decimal comma, so it is accepted as well.
+pub fn unknown_tag_f32(header: &ultrastar_txt::Header, tag: &str) -> Option<f32> {
+    unknown_tag(header, tag)?.replace(',', ".").parse().ok()
+}
+
+/// Whether the song has player change markers.
+pub fn is_duet(lines: &[Line]) -> bool {
+    lines.iter().any(|line| {
+        line.notes
+            .iter()
+            .any(|note| matches!(note, Note::PlayerChange { .. }))
+    })
+}
+
+/// A copy of `line` without notes and the start it should get, if known.
+fn empty_line(line: &Line, start: Option<i32>) -> (Line, Option<i32>) {
+    let mut empty = line.clone();
+    empty.notes.clear();
+    (empty, start)
+}
+
+/// Add a part of a line to the tracks of the given parts.
+///
+/// Lines that start after a player change begin where the previous line of
+/// their track ended.
+fn push_chunk(tracks: &mut [Vec<Line>], parts: &[usize], chunk: (Line, Option<i32>)) {
+    let (line, start) = chunk;
+    if line.notes.is_empty() {
+        return;
+    }
+    for &part in parts {
+        let mut line = line.clone();
+        line.start = match start {
+            Some(start) => start,
+            None => tracks[part].last().and_then(last_note_end).unwrap_or(0),
+        };
+        tracks[part].push(line);
+    }
+}
+
+/// The start and end beat of a note, `None` for player changes.
+pub fn note_span(note: &Note) -> Option<(i32, i32)> {
+    match *note {
+        Note::Regular {
+            start,
+            duration,
+            pitch: _,
+            text: _,
+        } => Some((start, start + duration)),
+        Note::Golden {
+            start,
+            duration,
+            pitch: _,
+            text: _,
+        } => Some((start, start + duration)),
+        Note::Freestyle {
+            start,
+            duration,
+            pitch: _,
+            text: _,
+        } => Some((start, start + duration)),
+        Note::PlayerChange { player: _ } => None,
+    }
+}
+
+/// The beat the first note of the line starts at.
+pub fn first_note_start(line: &Line) -> Option<i32> {
+    line.notes.iter().filter_map(note_span).map(|(start, _)| start).min()
+}
+
+/// The beat the last note of the line ends at.
+pub fn last_note_end(line: &Line) -> Option<i32> {
+    line.notes.iter().filter_map(note_span).map(|(_, end)| end).max()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note(start: i32, duration: i32) -> Note {
+        Note::Regular {
+            start,
+            duration,
+            pitch: 0,
+            text: "la".to_string(),
+        }
+    }
+
+    fn change(player: i32) -> Note {
+        Note::PlayerChange { player }
+    }
+
+    fn line(start: i32, notes: Vec<Note>) -> Line {
+        Line { start, rel: None, notes }
+    }
+
+    /// The start of every line of a track and the starts of its notes.
+    fn layout(track: &[Line]) -> Vec<(i32, Vec<i32>)> {
+        track
+            .iter()
+            .map(|line| {
+                let starts = line.notes.iter().filter_map(note_span).map(|(start, _)| start).collect();
+                (line.start, starts)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn songs_without_player_changes_have_one_track() {
+        let lines = vec![line(0, vec![note(0, 2)]), line(4, vec![note(5, 2)])];
+        assert_eq!(split_tracks(&lines), vec![lines]);
+    }
+
+    #[test]
+    fn sections_of_one_player_go_to_their_track() {
+        let lines = vec![
+            line(0, vec![change(1), note(0, 2), note(2, 2)]),
+            line(5, vec![note(6, 2)]),
+            line(10, vec![change(2), note(12, 2)]),
+            line(15, vec![note(16, 2)]),
+        ];
+        let tracks = split_tracks(&lines);
+        assert_eq!(layout(&tracks[0]), vec![(0, vec![0, 2]), (5, vec![6])]);
+        assert_eq!(layout(&tracks[1]), vec![(10, vec![12]), (15, vec![16])]);
+    }
+
+    #[test]
+    fn songs_for_the_second_player_only_leave_the_first_track_empty() {
+        let lines = vec![line(0, vec![change(2), note(0, 2)]), line(4, vec![note(5, 2)])];
+        let tracks = split_tracks(&lines);
+        assert!(tracks[0].is_empty());
+        assert_eq!(layout(&tracks[1]), vec![(0, vec![0]), (4, vec![5])]);
+    }
+
+    #[test]
+    fn lines_for_both_players_go_to_both_tracks() {
+        let lines = vec![
+            line(0, vec![change(1), note(0, 2)]),
+            line(4, vec![change(3), note(5, 2)]),
+            line(8, vec![change(2), note(9, 2)]),
+        ];
+        let tracks = split_tracks(&lines);
+        assert_eq!(layout(&tracks[0]), vec![(0, vec![0]), (4, vec![5])]);
+        assert_eq!(layout(&tracks[1]), vec![(4, vec![5]), (8, vec![9])]);
+    }
+
+    #[test]
+    fn splits_lines_where_the_player_changes() {
+        let lines = vec![
+            line(0, vec![change(2), note(0, 2)]),
+            line(3, vec![change(1), note(4, 2), change(2), note(6, 2), note(8, 1)]),
+            line(10, vec![note(11, 2)]),
+        ];
+        let tracks = split_tracks(&lines);
+        assert_eq!(layout(&tracks[0]), vec![(3, vec![4])]);
+        // the rest of the line starts where the previous line of the part ended
+        assert_eq!(layout(&tracks[1]), vec![(0, vec![0]), (2, vec![6, 8]), (10, vec![11])]);
+    }
+
+    #[test]
+    fn sorts_the_lines_of_every_track() {
+        // some files list every part in full, and the common lines at the end
+        let lines = vec![
+            line(0, vec![change(1), note(0, 2)]),
+            line(8, vec![note(9, 2)]),
+            line(0, vec![change(2), note(1, 2)]),
+            line(8, vec![note(10, 2)]),
+            line(4, vec![change(3), note(5, 2)]),
+        ];
+        let tracks = split_tracks(&lines);
+        assert_eq!(layout(&tracks[0]), vec![(0, vec![0]), (4, vec![5]), (8, vec![9])]);
+        assert_eq!(layout(&tracks[1]), vec![(0, vec![1]), (4, vec![5]), (8, vec![10])]);
+    }
+}
@@ -0,0 +1,232 @@
+//! Finds the line and the note of a track at any beat.
+//!
+//! Lines and notes are looked up from the beat alone, so seeking, rewinding
+//! or a jumping playback position can't get the lyrics out of sync.
+
+use ultrastar_txt::Line;
+
+use crate::song::note_span;
+
+/// Where a note of the track is and when it is sung.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NoteSpan {
+    /// The index of the line of the note.
+    pub line: usize,
+    /// The index of the note in its line.
+    pub note: usize,
+    pub start: i32,
+    /// The beat after the note.
+    pub end: i32,
+}
+
+/// The lines and notes of a track, indexed by the beat they are shown from.
+pub struct Timeline {
+    lines: Vec<Line>,
+    /// The indices of the lines, ordered by their start.
+    line_order: Vec<usize>,
+    /// The start of every line in `line_order`.
+    line_starts: Vec<i32>,
+    /// All notes, ordered by their start.
+    notes: Vec<NoteSpan>,
+}
+
+impl Timeline {
+    pub fn new(lines: Vec<Line>) -> Timeline {
+        // badly sorted songs have lines starting before the line in front of
+        // them, so the lines are searched in the order they start
+        let mut line_order: Vec<usize> = (0..lines.len()).collect();
+        line_order.sort_by_key(|index| lines[*index].start);
+        let line_starts = line_order.iter().map(|index| lines[*index].start).collect();
+
+        let mut notes: Vec<NoteSpan> = lines
+            .iter()
+            .enumerate()
+            .flat_map(|(line_index, line)| {
+                line.notes.iter().enumerate().filter_map(move |(note_index, note)| {
+                    let (start, end) = note_span(note)?;
+                    Some(NoteSpan {
+                        line: line_index,
+                        note: note_index,
+                        start,
+                        end,
+                    })
+                })
+            })
+            .collect();
+        notes.sort_by_key(|note| note.start);
+
+        Timeline {
+            lines,
+            line_order,
+            line_starts,
+            notes,
+        }
+    }
+
+    /// The index of the line to show at `beat`.
+    ///
+    /// Before the song starts, this is the first line.
+    pub fn line_index_at(&self, beat: f32) -> Option<usize> {
+        self.line_position_at(beat).map(|position| self.line_order[position])
+    }
+
+    /// The position in `line_order` of the line to show at `beat`.
+    fn line_position_at(&self, beat: f32) -> Option<usize> {
+        if self.lines.is_empty() {
+            return None;
+        }
+        // the first line is shown from the beginning, the others once their start passed
+        let started = self.line_starts[1..].partition_point(|start| (*start as f32) < beat);
+        Some(started)
+    }
+
+    /// The line to show at `beat`, if the track has any.
+    pub fn line_at(&self, beat: f32) -> Option<&Line> {
+        self.line_index_at(beat).map(|index| &self.lines[index])
+    }
+
+    /// The start of the line before the one shown at `beat`.
+    ///
+    /// Returns `None` in the first line.
+    pub fn previous_line_start(&self, beat: f32) -> Option<i32> {
+        let position = self.line_position_at(beat)?;
+        position.checked_sub(1).map(|previous| self.line_starts[previous])
+    }
+
+    /// The start of the line after the one shown at `beat`.
+    pub fn next_line_start(&self, beat: f32) -> Option<i32> {
+        let position = self.line_position_at(beat)?;
+        self.line_starts.get(position + 1).copied()
+    }
+
+    /// The note sung at `beat`, from its start up to its end.
+    ///
+    /// Of overlapping notes, the one started last is sung.
+    pub fn note_at(&self, beat: f32) -> Option<NoteSpan> {
+        let started = self.notes.partition_point(|note| (note.start as f32) <= beat);
+        let note = self.notes[..started].last()?;
+        if beat < note.end as f32 {
+            Some(*note)
+        } else {
+            None
+        }
+    }
+
+    /// The index of the note sung at `beat` in the line shown at `beat`.
+    pub fn current_note_index(&self, beat: f32) -> Option<usize> {
+        let line = self.line_index_at(beat)?;
+        self.note_at(beat).filter(|note| note.line == line).map(|note| note.note)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ultrastar_txt::Note;
+
+    fn note(start: i32, duration: i32) -> Note {
+        Note::Regular {
+            start,
+            duration,
+            pitch: 0,
+            text: "la".to_string(),
+        }
+    }
+
+    fn line(start: i32, notes: Vec<Note>) -> Line {
+        Line { start, rel: None, notes }
+    }
+
+    /// Three lines with gaps in between, the first one starting late.
+    fn timeline() -> Timeline {
+        Timeline::new(vec![
+            line(10, vec![note(10, 2), note(12, 2)]),
+            line(20, vec![note(22, 4)]),
+            line(30, vec![note(30, 1), note(33, 2)]),
+        ])
+    }
+
+    #[test]
+    fn shows_the_first_line_before_it_starts() {
+        let timeline = timeline();
+        assert_eq!(timeline.line_index_at(-100.0), Some(0));
+        assert_eq!(timeline.line_index_at(0.0), Some(0));
+        assert_eq!(timeline.note_at(5.0), None);
+        assert_eq!(timeline.previous_line_start(5.0), None);
+        assert_eq!(timeline.next_line_start(5.0), Some(20));
+    }
+
+    #[test]
+    fn keeps_a_line_until_the_next_one_starts() {
+        let timeline = timeline();
+        // after the last note of a line, before the next line
+        assert_eq!(timeline.line_index_at(17.0), Some(0));
+        assert_eq!(timeline.note_at(17.0), None);
+        // lines start after their start beat
+        assert_eq!(timeline.line_index_at(20.0), Some(0));
+        assert_eq!(timeline.line_index_at(20.5), Some(1));
+        // the last line is kept to the end
+        assert_eq!(timeline.line_index_at(1000.0), Some(2));
+    }
+
+    #[test]
+    fn finds_lines_and_notes_when_seeking_backwards() {
+        let timeline = timeline();
+        let beats = [34.0, 12.5, 31.0, 23.0, 10.0];
+        let found: Vec<(Option<usize>, Option<usize>)> = beats
+            .iter()
+            .map(|beat| (timeline.line_index_at(*beat), timeline.current_note_index(*beat)))
+            .collect();
+        assert_eq!(found, vec![(Some(2), Some(1)), (Some(0), Some(1)), (Some(2), None), (Some(1), Some(0)), (Some(0), Some(0))]);
+    }
+
+    #[test]
+    fn notes_end_before_the_next_one_starts() {
+        let timeline = timeline();
+        assert_eq!(timeline.current_note_index(11.9), Some(0));
+        assert_eq!(timeline.current_note_index(12.0), Some(1));
+        assert_eq!(timeline.current_note_index(14.0), None);
+        let note = timeline.note_at(23.0).unwrap();
+        assert_eq!((note.line, note.note, note.start, note.end), (1, 0, 22, 26));
+    }
+
+    #[test]
+    fn steps_to_the_previous_and_next_line() {
+        let timeline = timeline();
+        assert_eq!(timeline.previous_line_start(25.0), Some(10));
+        assert_eq!(timeline.next_line_start(25.0), Some(30));
+        assert_eq!(timeline.previous_line_start(35.0), Some(20));
+        assert_eq!(timeline.next_line_start(35.0), None);
+    }
+
+    #[test]
+    fn copes_with_lines_starting_before_the_previous_one() {
+        let timeline = Timeline::new(vec![
+            line(10, vec![note(10, 2)]),
+            line(30, vec![note(30, 2)]),
+            line(20, vec![note(22, 2)]),
+            line(40, vec![note(40, 2)]),
+        ]);
+        // every line is shown from its own start
+        let shown: Vec<Option<usize>> = [15.0, 25.0, 35.0, 45.0]
+            .iter()
+            .map(|beat| timeline.line_index_at(*beat))
+            .collect();
+        assert_eq!(shown, vec![Some(0), Some(2), Some(1), Some(3)]);
+        assert_eq!(timeline.next_line_start(15.0), Some(20));
+        assert_eq!(timeline.previous_line_start(35.0), Some(20));
+        // the note of the misplaced line is sung in it
+        assert_eq!(timeline.note_at(23.0).map(|note| note.line), Some(2));
+        assert_eq!(timeline.current_note_index(23.0), Some(0));
+    }
+
+    #[test]
+    fn tracks_without_lines_have_nothing_to_show() {
+        let timeline = Timeline::new(Vec::new());
+        assert_eq!(timeline.line_at(10.0), None);
+        assert_eq!(timeline.note_at(10.0), None);
+        assert_eq!(timeline.next_line_start(10.0), None);
+        assert_eq!(timeline.previous_line_start(10.0), None);
+    }
+}
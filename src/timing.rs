@@ -0,0 +1,270 @@
+//! Converts between the playback position in milliseconds and the beats of a
+//! song.
+//!
+//! The `#BPM` of a song counts in quarter beats, notes are placed on a grid of
+//! `#RESOLUTION` (usually 4) beats per quarter.  Beat 0 is at `#GAP` plus
+//! `#NOTESGAP` milliseconds.  `B` lines change the BPM in the middle of a song.
+//!
+//! Songs marked `#RELATIVE:yes` count the beats of every line from the start
+//! of the line, they are converted to absolute beats when loaded.
+
+use ultrastar_txt::{Header, Line, Note};
+
+use crate::song;
+
+/// The beats per quarter if the song does not give a `#RESOLUTION`.
+const DEFAULT_RESOLUTION: f32 = 4.0;
+
+/// A `B` line, changing the BPM from `beat` on.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BpmChange {
+    /// The absolute beat the new BPM starts at.
+    pub beat: f32,
+    pub bpm: f32,
+}
+
+/// A part of the song with a constant BPM.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Section {
+    /// The beat the section starts at.
+    beat: f32,
+    /// The time the section starts at in ms.
+    ms: f32,
+    beats_per_ms: f32,
+}
+
+/// Converts between milliseconds and beats of a song.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Timing {
+    /// The sections of the song, ordered by their start.
+    sections: Vec<Section>,
+}
+
+impl Timing {
+    /// Create the timing of a song that starts at `gap` ms with `bpm`.
+    pub fn new(bpm: f32, gap: f32, resolution: f32, changes: &[BpmChange]) -> Timing {
+        let beats_per_ms = |bpm: f32| bpm * resolution / 60_000.0;
+
+        let mut changes = changes.to_vec();
+        changes.sort_by(|a, b| a.beat.partial_cmp(&b.beat).unwrap_or(std::cmp::Ordering::Equal));
+
+        let mut sections = vec![Section {
+            beat: 0.0,
+            ms: gap,
+            beats_per_ms: beats_per_ms(bpm),
+        }];
+        for change in changes.iter().filter(|change| change.beat > 0.0 && change.bpm > 0.0) {
+            let last = sections[sections.len() - 1];
+            sections.push(Section {
+                beat: change.beat,
+                ms: last.ms + (change.beat - last.beat) / last.beats_per_ms,
+                beats_per_ms: beats_per_ms(change.bpm),
+            });
+        }
+        Timing { sections }
+    }
+
+    /// The timing given by the header of a song and its `B` lines.
+    pub fn from_header(header: &Header, changes: &[BpmChange]) -> Timing {
+        let gap = header.gap.unwrap_or(0.0) + song::unknown_tag_f32(header, "NOTESGAP").unwrap_or(0.0);
+        let resolution = song::unknown_tag_f32(header, "RESOLUTION")
+            .filter(|resolution| *resolution > 0.0)
+            .unwrap_or(DEFAULT_RESOLUTION);
+        Timing::new(header.bpm, gap, resolution, changes)
+    }
+
+    /// The beat at `ms`, negative before the first beat.
+    pub fn beat_at(&self, ms: f32) -> f32 {
+        let index = self.sections.partition_point(|section| section.ms <= ms).max(1);
+        let section = &self.sections[index - 1];
+        section.beat + (ms - section.ms) * section.beats_per_ms
+    }
+
+    /// The time of `beat` in ms.
+    pub fn ms_at(&self, beat: f32) -> f32 {
+        let index = self.sections.partition_point(|section| section.beat <= beat).max(1);
+        let section = &self.sections[index - 1];
+        section.ms + (beat - section.beat) / section.beats_per_ms
+    }
+}
+
+/// Convert the lines of a `#RELATIVE:yes` song to absolute beats.
+///
+/// In relative songs, the line break `- END START` ends the previous line at
+/// `END` and moves the origin of the following notes by `START` (or `END` if
+/// it is missing).
+pub fn make_absolute(lines: &mut [Line]) {
+    let mut origin = 0;
+    for line in lines.iter_mut() {
+        let line_break = origin + line.start;
+        origin += line.rel.unwrap_or(line.start);
+        line.start = line_break;
+        line.rel = None;
+        for note in line.notes.iter_mut() {
+            match note {
+                Note::Regular { start, .. } | Note::Golden { start, .. } | Note::Freestyle { start, .. } => {
+                    *start += origin
+                }
+                Note::PlayerChange { .. } => {}
+            }
+        }
+    }
+}
+
+/// Remove the `B` lines from the lines of a song file.
+///
+/// Returns the remaining text and the BPM changes with absolute beats.
+pub fn split_bpm_changes(txt: &str, relative: bool) -> (String, Vec<BpmChange>) {
+    let mut remaining = String::with_capacity(txt.len());
+    let mut changes = Vec::new();
+    // the origin of relative beats, moved by every line break
+    let mut origin = 0.0;
+    for line in txt.lines() {
+        let mut fields = line.split_whitespace();
+        match fields.next() {
+            Some("B") => {
+                let mut number = || fields.next().and_then(|field| field.replace(',', ".").parse::<f32>().ok());
+                if let (Some(beat), Some(bpm)) = (number(), number()) {
+                    changes.push(BpmChange {
+                        beat: if relative { origin + beat } else { beat },
+                        bpm,
+                    });
+                }
+                continue;
+            }
+            Some("-") if relative => {
+                let numbers: Vec<f32> = fields.filter_map(|field| field.parse().ok()).collect();
+                origin += numbers.get(1).or_else(|| numbers.first()).copied().unwrap_or(0.0);
+            }
+            _ => {}
+        }
+        remaining.push_str(line);
+        remaining.push('\n');
+    }
+    (remaining, changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note(start: i32, duration: i32) -> Note {
+        Note::Regular {
+            start,
+            duration,
+            pitch: 0,
+            text: "la".to_string(),
+        }
+    }
+
+    fn note_starts(lines: &[Line]) -> Vec<i32> {
+        lines
+            .iter()
+            .flat_map(|line| line.notes.iter().filter_map(song::note_span))
+            .map(|(start, _)| start)
+            .collect()
+    }
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!((actual - expected).abs() < 1e-3, "{} instead of {}", actual, expected);
+    }
+
+    /// A few lines of a song in absolute beats.
+    fn absolute_lines() -> Vec<Line> {
+        vec![
+            Line {
+                start: 0,
+                rel: None,
+                notes: vec![note(0, 4), note(4, 2), note(8, 4)],
+            },
+            Line {
+                start: 14,
+                rel: None,
+                notes: vec![note(16, 3), note(20, 3), note(24, 6)],
+            },
+            Line {
+                start: 32,
+                rel: None,
+                notes: vec![note(36, 2), note(40, 4)],
+            },
+        ]
+    }
+
+    /// The same lines with relative beats, as older tools write them.
+    fn relative_lines() -> Vec<Line> {
+        vec![
+            Line {
+                start: 0,
+                rel: None,
+                notes: vec![note(0, 4), note(4, 2), note(8, 4)],
+            },
+            Line {
+                start: 14,
+                rel: Some(16),
+                notes: vec![note(0, 3), note(4, 3), note(8, 6)],
+            },
+            Line {
+                start: 16,
+                rel: Some(20),
+                notes: vec![note(0, 2), note(4, 4)],
+            },
+        ]
+    }
+
+    #[test]
+    fn relative_songs_match_absolute_ones() {
+        let mut lines = relative_lines();
+        make_absolute(&mut lines);
+        assert_eq!(note_starts(&lines), note_starts(&absolute_lines()));
+        let line_starts: Vec<i32> = lines.iter().map(|line| line.start).collect();
+        assert_eq!(line_starts, vec![0, 14, 32]);
+    }
+
+    #[test]
+    fn line_breaks_without_start_move_the_origin_to_their_end() {
+        let mut lines = relative_lines();
+        lines[1].rel = None;
+        make_absolute(&mut lines);
+        assert_eq!(note_starts(&lines)[3], 14);
+    }
+
+    #[test]
+    fn converts_with_resolution_and_gap() {
+        // 120 BPM in quarters are 8 beats per second
+        let timing = Timing::new(120.0, 1000.0, DEFAULT_RESOLUTION, &[]);
+        assert_close(timing.beat_at(1000.0), 0.0);
+        assert_close(timing.beat_at(2000.0), 8.0);
+        assert_close(timing.beat_at(500.0), -4.0);
+        assert_close(timing.ms_at(8.0), 2000.0);
+
+        // a coarser grid has fewer beats in the same time
+        let timing = Timing::new(120.0, 1000.0, 2.0, &[]);
+        assert_close(timing.beat_at(2000.0), 4.0);
+    }
+
+    #[test]
+    fn follows_bpm_changes() {
+        let changes = [BpmChange { beat: 8.0, bpm: 240.0 }];
+        let timing = Timing::new(120.0, 0.0, DEFAULT_RESOLUTION, &changes);
+        assert_close(timing.ms_at(8.0), 1000.0);
+        // twice as fast after the change
+        assert_close(timing.ms_at(24.0), 2000.0);
+        assert_close(timing.beat_at(1500.0), 16.0);
+        assert_close(timing.beat_at(timing.ms_at(30.0)), 30.0);
+    }
+
+    #[test]
+    fn extracts_bpm_changes() {
+        let txt = ": 0 4 5 la\nB 8 240,5\n- 14 16\n: 0 3 5 la\nB 4 120\nE\n";
+
+        let (remaining, changes) = split_bpm_changes(txt, false);
+        assert_eq!(remaining, ": 0 4 5 la\n- 14 16\n: 0 3 5 la\nE\n");
+        assert_eq!(
+            changes,
+            vec![BpmChange { beat: 8.0, bpm: 240.5 }, BpmChange { beat: 4.0, bpm: 120.0 }]
+        );
+
+        let (_, changes) = split_bpm_changes(txt, true);
+        assert_eq!(changes[1].beat, 20.0);
+    }
+}